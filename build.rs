@@ -1,14 +1,58 @@
 use std::error::Error;
-use std::{env, path::PathBuf};
+use std::{env, fs, path::PathBuf};
+
+const REDACTED_MESSAGES: &[&str] = &[
+    "AdminAccount",
+    "UserAccount",
+    "CreateAdminAccountRequest",
+    "UpdateAdminAccountRequest",
+    "CreateUserAccountRequest",
+    "UpdateUserAccountRequest",
+    "LoginRequest",
+    "LoginResponse",
+    "VerifyAdminTotpRequest",
+    "EnrollTotpResponse",
+    "ConfirmTotpRequest",
+    "ConfirmTotpResponse",
+    "DisableTotpRequest",
+    "RefreshTokenRequest",
+    "ConfirmPasswordResetRequest",
+    "VerifyEmailRequest",
+];
 
 fn main() -> Result<(), Box<dyn Error>> {
     let out_dir = &PathBuf::from(env::var("OUT_DIR")?);
 
+    // Messages carrying credentials get hand-written, redacting Debug impls in
+    // src/redact.rs so they can't leak into the request logs.
+    let mut config = prost_build::Config::new();
+    config.skip_debug(
+        REDACTED_MESSAGES
+            .iter()
+            .map(|name| format!(".rust_ecom.{}", name)),
+    );
+
     tonic_build::configure()
+        .build_client(false)
         .file_descriptor_set_path(out_dir.join("rust_ecom_descriptor.bin"))
-        .compile(&["proto/rust_ecom.proto"], &["proto"])?;
+        .compile_with_config(
+            config,
+            &[
+                "proto/rust_ecom.proto",
+                "proto/google/rpc/status.proto",
+                "proto/google/rpc/error_details.proto",
+            ],
+            &["proto"],
+        )?;
 
-    tonic_build::compile_protos("proto/rust_ecom.proto")?;
+    // tonic-build 0.11 has no codec option for prost services, so swap the
+    // codec in the generated servers for the one that validates requests.
+    let generated = out_dir.join("rust_ecom.rs");
+    let code = fs::read_to_string(&generated)?.replace(
+        "tonic::codec::ProstCodec::default()",
+        "crate::validate::ValidatingCodec::default()",
+    );
+    fs::write(&generated, code)?;
 
     println!("cargo:rerun-if-changed=migrations");
 
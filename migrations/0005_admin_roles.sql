@@ -0,0 +1,44 @@
+CREATE TABLE roles (
+    role TEXT PRIMARY KEY
+);
+
+CREATE TABLE role_permissions (
+    role TEXT NOT NULL REFERENCES roles (role) ON DELETE CASCADE,
+    permission TEXT NOT NULL,
+    PRIMARY KEY (role, permission)
+);
+
+CREATE TABLE admin_roles (
+    admin_id INT NOT NULL REFERENCES admins (admin_id) ON DELETE CASCADE,
+    role TEXT NOT NULL REFERENCES roles (role) ON DELETE CASCADE,
+    PRIMARY KEY (admin_id, role)
+);
+
+INSERT INTO roles (role)
+    VALUES ('owner'), ('catalog_manager'), ('order_manager'), ('support'), ('read_only');
+
+INSERT INTO role_permissions (role, permission) VALUES
+    ('owner', 'catalog:read'),
+    ('owner', 'catalog:write'),
+    ('owner', 'orders:read'),
+    ('owner', 'orders:write'),
+    ('owner', 'users:read'),
+    ('owner', 'users:write'),
+    ('owner', 'admins:read'),
+    ('owner', 'admins:write'),
+    ('catalog_manager', 'catalog:read'),
+    ('catalog_manager', 'catalog:write'),
+    ('order_manager', 'catalog:read'),
+    ('order_manager', 'orders:read'),
+    ('order_manager', 'orders:write'),
+    ('support', 'catalog:read'),
+    ('support', 'orders:read'),
+    ('support', 'users:read'),
+    ('support', 'users:write'),
+    ('read_only', 'catalog:read'),
+    ('read_only', 'orders:read'),
+    ('read_only', 'users:read'),
+    ('read_only', 'admins:read');
+
+-- Every admin had full access before roles existed.
+INSERT INTO admin_roles (admin_id, role) SELECT admin_id, 'owner' FROM admins;
@@ -0,0 +1,16 @@
+CREATE TABLE sessions (
+    session_id SERIAL PRIMARY KEY,
+    user_id INT REFERENCES users (user_id) ON DELETE CASCADE,
+    admin_id INT REFERENCES admins (admin_id) ON DELETE CASCADE,
+    refresh_token_hash TEXT NOT NULL UNIQUE,
+    previous_refresh_token_hash TEXT,
+    created_at FLOAT NOT NULL,
+    last_used_at FLOAT NOT NULL,
+    expires_at FLOAT NOT NULL,
+    revoked_at FLOAT,
+    CHECK ((user_id IS NULL) <> (admin_id IS NULL))
+);
+
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
+CREATE INDEX sessions_admin_id_idx ON sessions (admin_id);
+CREATE INDEX sessions_previous_refresh_token_hash_idx ON sessions (previous_refresh_token_hash);
@@ -0,0 +1,34 @@
+CREATE TABLE admin_totp (
+    admin_id INT PRIMARY KEY REFERENCES admins (admin_id) ON DELETE CASCADE,
+    secret BYTEA NOT NULL,
+    enabled BOOLEAN NOT NULL DEFAULT FALSE,
+    last_used_step BIGINT,
+    created_at FLOAT NOT NULL
+);
+
+CREATE TABLE admin_recovery_codes (
+    admin_id INT NOT NULL REFERENCES admins (admin_id) ON DELETE CASCADE,
+    code_hash TEXT NOT NULL,
+    used_at FLOAT,
+    PRIMARY KEY (admin_id, code_hash)
+);
+
+ALTER TABLE roles ADD COLUMN requires_totp BOOLEAN NOT NULL DEFAULT FALSE;
+
+UPDATE roles SET requires_totp = TRUE WHERE role = 'owner';
+
+-- Failed TOTP and recovery code attempts in a row, counted before each check
+-- so parallel guesses can't slip past the limit. Reaching the limit locks the
+-- admin out until locked_until.
+CREATE TABLE admin_totp_attempts (
+    admin_id INT PRIMARY KEY REFERENCES admins (admin_id) ON DELETE CASCADE,
+    failed_attempts INT NOT NULL,
+    locked_until FLOAT
+);
+
+-- Outstanding TOTP login challenges, each usable for a single attempt.
+CREATE TABLE admin_totp_challenges (
+    challenge_id_hash TEXT PRIMARY KEY,
+    admin_id INT NOT NULL REFERENCES admins (admin_id) ON DELETE CASCADE,
+    expires_at FLOAT NOT NULL
+);
@@ -0,0 +1,21 @@
+UPDATE users SET username = trim(username), email = lower(trim(email));
+UPDATE admins SET username = trim(username), email = lower(trim(email));
+
+-- Refuse to guess which of two clashing accounts to keep.
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM users GROUP BY lower(username) HAVING count(*) > 1)
+        OR EXISTS (SELECT 1 FROM users GROUP BY email HAVING count(*) > 1)
+        OR EXISTS (SELECT 1 FROM admins GROUP BY lower(username) HAVING count(*) > 1)
+        OR EXISTS (SELECT 1 FROM admins WHERE email <> '' GROUP BY email HAVING count(*) > 1)
+    THEN
+        RAISE EXCEPTION 'Usernames or emails differing only in case must be merged or renamed before this migration can run';
+    END IF;
+END
+$$;
+
+CREATE UNIQUE INDEX users_username_lower_key ON users (lower(username));
+CREATE UNIQUE INDEX users_email_lower_key ON users (lower(email));
+CREATE UNIQUE INDEX admins_username_lower_key ON admins (lower(username));
+-- Admins created by bootstrap-admin may have no email.
+CREATE UNIQUE INDEX admins_email_lower_key ON admins (lower(email)) WHERE email <> '';
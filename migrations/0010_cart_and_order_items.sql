@@ -0,0 +1,35 @@
+CREATE TABLE cart_items (
+    user_id INT NOT NULL REFERENCES users (user_id) ON DELETE CASCADE,
+    product_id INT NOT NULL REFERENCES products (product_id) ON DELETE CASCADE,
+    quantity INT NOT NULL CHECK (quantity > 0),
+    PRIMARY KEY (user_id, product_id)
+);
+
+-- Orders keep what was bought, so ordered products can't be deleted.
+CREATE TABLE order_items (
+    order_id INT NOT NULL REFERENCES orders (order_id) ON DELETE CASCADE,
+    product_id INT NOT NULL REFERENCES products (product_id) ON DELETE RESTRICT,
+    quantity INT NOT NULL CHECK (quantity > 0),
+    unit_price FLOAT NOT NULL,
+    name_snapshot TEXT NOT NULL,
+    PRIMARY KEY (order_id, product_id)
+);
+
+-- Repeated ids become quantities. Ids of products that no longer exist are
+-- dropped, and past orders get today's prices since none were recorded.
+INSERT INTO cart_items (user_id, product_id, quantity)
+SELECT users.user_id, products.product_id, count(*)
+FROM users
+CROSS JOIN unnest(users.products) AS cart (product_id)
+JOIN products ON products.product_id = cart.product_id
+GROUP BY users.user_id, products.product_id;
+
+INSERT INTO order_items (order_id, product_id, quantity, unit_price, name_snapshot)
+SELECT orders.order_id, products.product_id, count(*), products.price, products.name
+FROM orders
+CROSS JOIN unnest(orders.products) AS ordered (product_id)
+JOIN products ON products.product_id = ordered.product_id
+GROUP BY orders.order_id, products.product_id;
+
+ALTER TABLE users DROP COLUMN products;
+ALTER TABLE orders DROP COLUMN products;
@@ -0,0 +1,15 @@
+-- Results of mutating RPCs sent with an `idempotency-key` header. `scope` is
+-- the caller (`user:<id>` or `admin:<id>`, or `anonymous:<hex fingerprint>`
+-- without one), `fingerprint` the SHA-256 of the method path and request
+-- body, and `response` the encoded reply, NULL while the first attempt is
+-- still running.
+CREATE TABLE idempotency_keys (
+    scope TEXT NOT NULL,
+    key TEXT NOT NULL,
+    fingerprint BYTEA NOT NULL,
+    response BYTEA,
+    created_at FLOAT NOT NULL,
+    PRIMARY KEY (scope, key)
+);
+
+CREATE INDEX idempotency_keys_created_at_idx ON idempotency_keys (created_at);
@@ -0,0 +1,41 @@
+-- Every status change, including the one that created the order. Changes are
+-- made either by an admin or by the customer.
+CREATE TABLE order_status_history (
+    history_id SERIAL PRIMARY KEY,
+    order_id INT NOT NULL REFERENCES orders (order_id) ON DELETE CASCADE,
+    from_status TEXT,
+    to_status TEXT NOT NULL,
+    admin_id INT REFERENCES admins (admin_id) ON DELETE SET NULL,
+    user_id INT REFERENCES users (user_id) ON DELETE SET NULL,
+    note TEXT NOT NULL DEFAULT '',
+    changed_at FLOAT NOT NULL
+);
+
+CREATE INDEX order_status_history_order_id_idx ON order_status_history (order_id);
+
+-- Free-text statuses that aren't one of the known ones become pending; the
+-- original text is kept in the history.
+WITH migrated AS (
+    SELECT order_id, status AS original,
+        CASE
+            WHEN lower(trim(status)) = 'canceled' THEN 'cancelled'
+            WHEN lower(trim(status)) IN ('pending', 'paid', 'fulfilling', 'shipped', 'delivered', 'cancelled', 'refunded')
+                THEN lower(trim(status))
+            ELSE 'pending'
+        END AS status
+    FROM orders
+), updated AS (
+    UPDATE orders SET status = migrated.status
+    FROM migrated
+    WHERE orders.order_id = migrated.order_id
+    RETURNING orders.order_id, orders.status, orders.created_at, migrated.original
+)
+INSERT INTO order_status_history (order_id, to_status, note, changed_at)
+SELECT order_id, status,
+    CASE WHEN original = status THEN '' ELSE format('Migrated from status %L', original) END,
+    created_at
+FROM updated;
+
+ALTER TABLE orders ADD CONSTRAINT orders_status_check CHECK (
+    status IN ('pending', 'paid', 'fulfilling', 'shipped', 'delivered', 'cancelled', 'refunded')
+);
@@ -0,0 +1,30 @@
+-- Every product gets its own stock row, so a missing row can't pass for an
+-- empty shelf. Existing products start at zero.
+CREATE TABLE inventory (
+    product_id INT PRIMARY KEY REFERENCES products (product_id) ON DELETE CASCADE,
+    stock_on_hand INT NOT NULL DEFAULT 0 CONSTRAINT inventory_stock_on_hand_check CHECK (stock_on_hand >= 0),
+    reserved INT NOT NULL DEFAULT 0 CONSTRAINT inventory_reserved_check CHECK (reserved >= 0),
+    CONSTRAINT inventory_available_check CHECK (reserved <= stock_on_hand)
+);
+
+INSERT INTO inventory (product_id)
+SELECT product_id FROM products;
+
+CREATE FUNCTION create_inventory() RETURNS TRIGGER AS $$
+BEGIN
+    INSERT INTO inventory (product_id) VALUES (NEW.product_id);
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER products_create_inventory AFTER INSERT ON products
+    FOR EACH ROW EXECUTE FUNCTION create_inventory();
+
+-- Stock held for an order between checkout and fulfilment or cancellation.
+-- Orders placed before stock was tracked have none.
+CREATE TABLE stock_reservations (
+    order_id INT NOT NULL REFERENCES orders (order_id) ON DELETE CASCADE,
+    product_id INT NOT NULL REFERENCES products (product_id),
+    quantity INT NOT NULL CHECK (quantity > 0),
+    PRIMARY KEY (order_id, product_id)
+);
@@ -0,0 +1,47 @@
+-- Every change to `inventory`, so stock levels can be explained and rebuilt.
+-- `on_hand_change` and `reserved_change` are what the movement added to
+-- `stock_on_hand` and `reserved`.
+CREATE TABLE inventory_movements (
+    movement_id SERIAL PRIMARY KEY,
+    product_id INT NOT NULL REFERENCES products (product_id) ON DELETE CASCADE,
+    kind TEXT NOT NULL CHECK (
+        kind IN ('receipt', 'sale', 'return', 'adjustment', 'damage', 'transfer', 'reservation', 'release')
+    ),
+    on_hand_change INT NOT NULL,
+    reserved_change INT NOT NULL,
+    order_id INT REFERENCES orders (order_id) ON DELETE SET NULL,
+    admin_id INT REFERENCES admins (admin_id) ON DELETE SET NULL,
+    note TEXT NOT NULL DEFAULT '',
+    created_at FLOAT NOT NULL,
+    CHECK (on_hand_change <> 0 OR reserved_change <> 0)
+);
+
+CREATE INDEX inventory_movements_product_id_created_at_idx
+    ON inventory_movements (product_id, created_at);
+CREATE INDEX inventory_movements_created_at_idx ON inventory_movements (created_at);
+
+-- Movements are append-only. Only the cascades from deleting a product, order
+-- or admin (which run one trigger level down) may touch them.
+CREATE FUNCTION inventory_movements_append_only() RETURNS trigger AS $$
+BEGIN
+    IF pg_trigger_depth() <= 1 THEN
+        RAISE EXCEPTION 'inventory_movements is append-only'
+            USING ERRCODE = 'check_violation', CONSTRAINT = 'inventory_movements_append_only';
+    END IF;
+    RETURN CASE TG_OP WHEN 'DELETE' THEN OLD ELSE NEW END;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER inventory_movements_append_only
+    BEFORE UPDATE OR DELETE ON inventory_movements
+    FOR EACH ROW EXECUTE FUNCTION inventory_movements_append_only();
+
+-- Opening balances for stock recorded before the ledger existed.
+INSERT INTO inventory_movements (product_id, kind, on_hand_change, reserved_change, note, created_at)
+SELECT product_id, 'adjustment', stock_on_hand, 0, 'Opening balance', extract(epoch FROM now())
+FROM inventory
+WHERE stock_on_hand <> 0;
+
+INSERT INTO inventory_movements (product_id, kind, on_hand_change, reserved_change, order_id, note, created_at)
+SELECT product_id, 'reservation', 0, quantity, order_id, 'Opening balance', extract(epoch FROM now())
+FROM stock_reservations;
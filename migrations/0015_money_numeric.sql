@@ -0,0 +1,16 @@
+-- Amounts are exact decimals to four places, with an ISO 4217 currency code.
+-- Every existing amount was in the store's single currency, US dollars.
+ALTER TABLE products
+    ALTER COLUMN price TYPE NUMERIC(19, 4) USING round(price::NUMERIC, 4),
+    ADD COLUMN currency_code TEXT NOT NULL DEFAULT 'USD' CHECK (currency_code ~ '^[A-Z]{3}$');
+
+ALTER TABLE orders
+    ALTER COLUMN total TYPE NUMERIC(19, 4) USING round(total::NUMERIC, 4),
+    ADD COLUMN currency_code TEXT NOT NULL DEFAULT 'USD' CHECK (currency_code ~ '^[A-Z]{3}$');
+
+-- Line items are in their order's currency.
+ALTER TABLE order_items
+    ALTER COLUMN unit_price TYPE NUMERIC(19, 4) USING round(unit_price::NUMERIC, 4);
+
+ALTER TABLE products ALTER COLUMN currency_code DROP DEFAULT;
+ALTER TABLE orders ALTER COLUMN currency_code DROP DEFAULT;
@@ -0,0 +1,73 @@
+-- Epoch-second floats become TIMESTAMPTZ, and creation times default to now.
+ALTER TABLE products
+    ALTER COLUMN created_at TYPE TIMESTAMPTZ USING to_timestamp(created_at),
+    ALTER COLUMN created_at SET DEFAULT now();
+ALTER TABLE orders
+    ALTER COLUMN created_at TYPE TIMESTAMPTZ USING to_timestamp(created_at),
+    ALTER COLUMN created_at SET DEFAULT now();
+ALTER TABLE admins
+    ALTER COLUMN created_at TYPE TIMESTAMPTZ USING to_timestamp(created_at),
+    ALTER COLUMN created_at SET DEFAULT now();
+ALTER TABLE users
+    ALTER COLUMN created_at TYPE TIMESTAMPTZ USING to_timestamp(created_at),
+    ALTER COLUMN created_at SET DEFAULT now();
+
+ALTER TABLE order_status_history
+    ALTER COLUMN changed_at TYPE TIMESTAMPTZ USING to_timestamp(changed_at),
+    ALTER COLUMN changed_at SET DEFAULT now();
+ALTER TABLE inventory_movements
+    ALTER COLUMN created_at TYPE TIMESTAMPTZ USING to_timestamp(created_at),
+    ALTER COLUMN created_at SET DEFAULT now();
+
+ALTER TABLE sessions
+    ALTER COLUMN created_at TYPE TIMESTAMPTZ USING to_timestamp(created_at),
+    ALTER COLUMN created_at SET DEFAULT now(),
+    ALTER COLUMN last_used_at TYPE TIMESTAMPTZ USING to_timestamp(last_used_at),
+    ALTER COLUMN last_used_at SET DEFAULT now(),
+    ALTER COLUMN expires_at TYPE TIMESTAMPTZ USING to_timestamp(expires_at),
+    ALTER COLUMN revoked_at TYPE TIMESTAMPTZ USING to_timestamp(revoked_at);
+ALTER TABLE account_tokens
+    ALTER COLUMN created_at TYPE TIMESTAMPTZ USING to_timestamp(created_at),
+    ALTER COLUMN created_at SET DEFAULT now(),
+    ALTER COLUMN expires_at TYPE TIMESTAMPTZ USING to_timestamp(expires_at),
+    ALTER COLUMN used_at TYPE TIMESTAMPTZ USING to_timestamp(used_at);
+ALTER TABLE admin_totp
+    ALTER COLUMN created_at TYPE TIMESTAMPTZ USING to_timestamp(created_at),
+    ALTER COLUMN created_at SET DEFAULT now();
+ALTER TABLE admin_recovery_codes
+    ALTER COLUMN used_at TYPE TIMESTAMPTZ USING to_timestamp(used_at);
+ALTER TABLE admin_totp_attempts
+    ALTER COLUMN locked_until TYPE TIMESTAMPTZ USING to_timestamp(locked_until);
+ALTER TABLE admin_totp_challenges
+    ALTER COLUMN expires_at TYPE TIMESTAMPTZ USING to_timestamp(expires_at);
+ALTER TABLE idempotency_keys
+    ALTER COLUMN created_at TYPE TIMESTAMPTZ USING to_timestamp(created_at),
+    ALTER COLUMN created_at SET DEFAULT now();
+
+CREATE FUNCTION set_updated_at() RETURNS TRIGGER AS $$
+BEGIN
+    NEW.updated_at = now();
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Rows that were never updated count as updated when they were created.
+ALTER TABLE products ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now();
+UPDATE products SET updated_at = created_at;
+CREATE TRIGGER products_set_updated_at BEFORE UPDATE ON products
+    FOR EACH ROW WHEN (OLD IS DISTINCT FROM NEW) EXECUTE FUNCTION set_updated_at();
+
+ALTER TABLE orders ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now();
+UPDATE orders SET updated_at = created_at;
+CREATE TRIGGER orders_set_updated_at BEFORE UPDATE ON orders
+    FOR EACH ROW WHEN (OLD IS DISTINCT FROM NEW) EXECUTE FUNCTION set_updated_at();
+
+ALTER TABLE admins ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now();
+UPDATE admins SET updated_at = created_at;
+CREATE TRIGGER admins_set_updated_at BEFORE UPDATE ON admins
+    FOR EACH ROW WHEN (OLD IS DISTINCT FROM NEW) EXECUTE FUNCTION set_updated_at();
+
+ALTER TABLE users ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now();
+UPDATE users SET updated_at = created_at;
+CREATE TRIGGER users_set_updated_at BEFORE UPDATE ON users
+    FOR EACH ROW WHEN (OLD IS DISTINCT FROM NEW) EXECUTE FUNCTION set_updated_at();
@@ -0,0 +1,37 @@
+-- Bumped on every change, so writers can tell whether a row changed since
+-- they read it. Logins that rehash a password set `app.rehash` for their
+-- transaction, as that isn't a change to the account.
+CREATE FUNCTION bump_version() RETURNS TRIGGER AS $$
+BEGIN
+    NEW.version = OLD.version + 1;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+ALTER TABLE products ADD COLUMN version INT NOT NULL DEFAULT 1;
+CREATE TRIGGER products_bump_version BEFORE UPDATE ON products
+    FOR EACH ROW WHEN (OLD IS DISTINCT FROM NEW) EXECUTE FUNCTION bump_version();
+
+ALTER TABLE orders ADD COLUMN version INT NOT NULL DEFAULT 1;
+CREATE TRIGGER orders_bump_version BEFORE UPDATE ON orders
+    FOR EACH ROW WHEN (OLD IS DISTINCT FROM NEW) EXECUTE FUNCTION bump_version();
+
+ALTER TABLE admins ADD COLUMN version INT NOT NULL DEFAULT 1;
+CREATE TRIGGER admins_bump_version BEFORE UPDATE ON admins
+    FOR EACH ROW WHEN (OLD IS DISTINCT FROM NEW AND current_setting('app.rehash', true) IS DISTINCT FROM 'on')
+    EXECUTE FUNCTION bump_version();
+
+DROP TRIGGER admins_set_updated_at ON admins;
+CREATE TRIGGER admins_set_updated_at BEFORE UPDATE ON admins
+    FOR EACH ROW WHEN (OLD IS DISTINCT FROM NEW AND current_setting('app.rehash', true) IS DISTINCT FROM 'on')
+    EXECUTE FUNCTION set_updated_at();
+
+ALTER TABLE users ADD COLUMN version INT NOT NULL DEFAULT 1;
+CREATE TRIGGER users_bump_version BEFORE UPDATE ON users
+    FOR EACH ROW WHEN (OLD IS DISTINCT FROM NEW AND current_setting('app.rehash', true) IS DISTINCT FROM 'on')
+    EXECUTE FUNCTION bump_version();
+
+DROP TRIGGER users_set_updated_at ON users;
+CREATE TRIGGER users_set_updated_at BEFORE UPDATE ON users
+    FOR EACH ROW WHEN (OLD IS DISTINCT FROM NEW AND current_setting('app.rehash', true) IS DISTINCT FROM 'on')
+    EXECUTE FUNCTION set_updated_at();
@@ -0,0 +1,333 @@
+use sqlx::types::time::OffsetDateTime;
+use sqlx::{query, query_scalar};
+
+use crate::error::{self, DbError};
+use crate::mailer::{Email, Mailer};
+use crate::page::{Field, Kind, Listing};
+use crate::{proto, timestamp, token};
+
+const PASSWORD_RESET_TTL_SECS: f64 = 60.0 * 60.0;
+const EMAIL_VERIFICATION_TTL_SECS: f64 = 60.0 * 60.0 * 24.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Purpose {
+    PasswordReset,
+    EmailVerification,
+}
+
+impl Purpose {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Purpose::PasswordReset => "password_reset",
+            Purpose::EmailVerification => "email_verification",
+        }
+    }
+
+    fn ttl_secs(&self) -> f64 {
+        match self {
+            Purpose::PasswordReset => PASSWORD_RESET_TTL_SECS,
+            Purpose::EmailVerification => EMAIL_VERIFICATION_TTL_SECS,
+        }
+    }
+
+    fn email(&self, to: &str, token: &str) -> Email {
+        let (subject, body) = match self {
+            Purpose::PasswordReset => (
+                "Reset your password",
+                format!(
+                    "Use this code to reset your password. It expires in one hour.\n\n{}\n\nIf you did not ask for a reset, you can ignore this message.",
+                    token
+                ),
+            ),
+            Purpose::EmailVerification => (
+                "Verify your email address",
+                format!(
+                    "Use this code to verify your email address. It expires in 24 hours.\n\n{}",
+                    token
+                ),
+            ),
+        };
+
+        Email {
+            to: to.to_owned(),
+            subject: subject.to_owned(),
+            body,
+        }
+    }
+}
+
+/// An `admins` row. Its Debug impl would print the password hash, so it is
+/// converted to a [`proto::AdminAccount`] before logging.
+#[derive(sqlx::FromRow)]
+pub(crate) struct AdminRow {
+    pub(crate) admin_id: i32,
+    pub(crate) username: String,
+    pub(crate) password: String,
+    pub(crate) email: String,
+    pub(crate) created_at: OffsetDateTime,
+    pub(crate) updated_at: OffsetDateTime,
+    pub(crate) version: i32,
+}
+
+pub(crate) const ADMIN_LISTING: Listing = Listing {
+    select: "*",
+    from: "admins",
+    id: "admin_id",
+    fields: &[
+        Field {
+            name: "admin_id",
+            column: "admin_id",
+            kind: Kind::Int,
+            sortable: true,
+        },
+        Field {
+            name: "username",
+            column: "username",
+            kind: Kind::Text,
+            sortable: true,
+        },
+        Field {
+            name: "email",
+            column: "email",
+            kind: Kind::Text,
+            sortable: true,
+        },
+        Field {
+            name: "created_at",
+            column: "created_at",
+            kind: Kind::Timestamp,
+            sortable: true,
+        },
+        Field {
+            name: "updated_at",
+            column: "updated_at",
+            kind: Kind::Timestamp,
+            sortable: true,
+        },
+    ],
+    default_order: "admin_id",
+};
+
+impl From<AdminRow> for proto::AdminAccount {
+    fn from(row: AdminRow) -> Self {
+        Self {
+            admin_id: row.admin_id,
+            username: row.username,
+            password: row.password,
+            email: row.email,
+            created_at: Some(timestamp::to_proto(row.created_at)),
+            updated_at: Some(timestamp::to_proto(row.updated_at)),
+            version: row.version,
+        }
+    }
+}
+
+/// A `users` row, likewise converted to a [`proto::UserAccount`] before logging.
+#[derive(sqlx::FromRow)]
+pub(crate) struct UserRow {
+    pub(crate) user_id: i32,
+    pub(crate) username: String,
+    pub(crate) password: String,
+    pub(crate) email: String,
+    pub(crate) created_at: OffsetDateTime,
+    pub(crate) orders: Vec<i32>,
+    pub(crate) email_verified: bool,
+    pub(crate) updated_at: OffsetDateTime,
+    pub(crate) version: i32,
+}
+
+pub(crate) const USER_LISTING: Listing = Listing {
+    select: "*",
+    from: "users",
+    id: "user_id",
+    fields: &[
+        Field {
+            name: "user_id",
+            column: "user_id",
+            kind: Kind::Int,
+            sortable: true,
+        },
+        Field {
+            name: "username",
+            column: "username",
+            kind: Kind::Text,
+            sortable: true,
+        },
+        Field {
+            name: "email",
+            column: "email",
+            kind: Kind::Text,
+            sortable: true,
+        },
+        Field {
+            name: "email_verified",
+            column: "email_verified",
+            kind: Kind::Bool,
+            sortable: false,
+        },
+        Field {
+            name: "created_at",
+            column: "created_at",
+            kind: Kind::Timestamp,
+            sortable: true,
+        },
+        Field {
+            name: "updated_at",
+            column: "updated_at",
+            kind: Kind::Timestamp,
+            sortable: true,
+        },
+    ],
+    default_order: "user_id",
+};
+
+impl From<UserRow> for proto::UserAccount {
+    fn from(row: UserRow) -> Self {
+        Self {
+            user_id: row.user_id,
+            username: row.username,
+            password: row.password,
+            email: row.email,
+            orders: row.orders,
+            email_verified: row.email_verified,
+            created_at: Some(timestamp::to_proto(row.created_at)),
+            updated_at: Some(timestamp::to_proto(row.updated_at)),
+            version: row.version,
+        }
+    }
+}
+
+/// Issues a single-use token for `purpose`, invalidating any earlier unused one.
+pub(crate) async fn issue_token(
+    conn: &mut sqlx::PgConnection,
+    purpose: Purpose,
+    user_id: i32,
+    email: &str,
+) -> Result<String, tonic::Status> {
+    let token = token::generate();
+
+    query!(
+        "UPDATE account_tokens SET used_at = now() WHERE user_id = $1 AND purpose = $2 AND used_at IS NULL;",
+        user_id,
+        purpose.as_str()
+    )
+    .execute(&mut *conn)
+    .await
+    .map_err(DbError::from)?;
+
+    query!(
+        "INSERT INTO account_tokens (token_hash, user_id, purpose, email, expires_at) VALUES ($1, $2, $3, $4, now() + make_interval(secs => $5));",
+        token::digest(&token),
+        user_id,
+        purpose.as_str(),
+        email,
+        purpose.ttl_secs()
+    )
+    .execute(&mut *conn)
+    .await
+    .map_err(DbError::from)?;
+
+    Ok(token)
+}
+
+/// Mails a token from [`issue_token`] to `email`. Delivery failures are logged,
+/// not returned, so callers cannot be used to probe which addresses have accounts.
+pub(crate) async fn mail_token(
+    mailer: &dyn Mailer,
+    purpose: Purpose,
+    user_id: i32,
+    email: &str,
+    token: &str,
+) {
+    if let Err(e) = mailer.send(purpose.email(email, token)).await {
+        tracing::error!(
+            error = ?e,
+            user_id,
+            purpose = purpose.as_str(),
+            "Failed to mail account token"
+        );
+    }
+}
+
+/// Issues a token for `purpose` and mails it to `email`.
+pub(crate) async fn send_token(
+    db_pool: &sqlx::PgPool,
+    mailer: &dyn Mailer,
+    purpose: Purpose,
+    user_id: i32,
+    email: &str,
+) -> Result<(), tonic::Status> {
+    let mut tx = db_pool.begin().await.map_err(DbError::from)?;
+    let token = issue_token(&mut tx, purpose, user_id, email).await?;
+    tx.commit().await.map_err(DbError::from)?;
+
+    mail_token(mailer, purpose, user_id, email, &token).await;
+
+    Ok(())
+}
+
+/// Marks a token as used and returns the user and email it was issued for.
+pub(crate) async fn consume_token<'e, E>(
+    executor: E,
+    purpose: Purpose,
+    token: &str,
+) -> Result<(i32, String), tonic::Status>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    query!(
+        "UPDATE account_tokens SET used_at = now() WHERE token_hash = $1 AND purpose = $2 AND used_at IS NULL AND expires_at > now() RETURNING user_id, email;",
+        token::digest(token),
+        purpose.as_str()
+    )
+    .fetch_optional(executor)
+    .await
+    .map_err(DbError::from)?
+    .map(|row| (row.user_id, row.email))
+    .ok_or_else(|| tonic::Status::invalid_argument("Invalid or expired token"))
+}
+
+/// The user registered under an already normalized `email`.
+pub(crate) async fn user_with_email(
+    db_pool: &sqlx::PgPool,
+    email: &str,
+) -> Result<Option<i32>, tonic::Status> {
+    query_scalar!(
+        "SELECT user_id FROM users WHERE lower(email) = lower($1);",
+        email
+    )
+    .fetch_optional(db_pool)
+    .await
+    .map_err(|e| DbError::from(e).into())
+}
+
+/// Why a write conditioned on an expected version matched no admin.
+pub(crate) async fn admin_version_mismatch(db_pool: &sqlx::PgPool, admin_id: i32) -> tonic::Status {
+    match query_scalar!("SELECT version FROM admins WHERE admin_id = $1;", admin_id)
+        .fetch_one(db_pool)
+        .await
+    {
+        Ok(version) => error::version_mismatch(version),
+        Err(e) => DbError::from(e).into(),
+    }
+}
+
+/// Why a write conditioned on an expected version matched no user.
+pub(crate) async fn user_version_mismatch(db_pool: &sqlx::PgPool, user_id: i32) -> tonic::Status {
+    match query_scalar!("SELECT version FROM users WHERE user_id = $1;", user_id)
+        .fetch_one(db_pool)
+        .await
+    {
+        Ok(version) => error::version_mismatch(version),
+        Err(e) => DbError::from(e).into(),
+    }
+}
+
+/// Usernames keep their case but are unique regardless of it.
+pub(crate) fn normalize_username(username: &str) -> &str {
+    username.trim()
+}
+
+pub(crate) fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
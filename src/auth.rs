@@ -0,0 +1,377 @@
+use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sqlx::query;
+use std::{
+    convert::Infallible,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tonic::{
+    body::BoxBody,
+    codegen::{http, BoxFuture, Service},
+    server::NamedService,
+    transport::Body,
+};
+
+use crate::error::DbError;
+use crate::{rbac, session, token};
+
+pub(crate) const ACCESS_TOKEN_TTL_SECS: u64 = 60 * 60;
+
+pub(crate) static KEYS: Lazy<Keys> = Lazy::new(|| {
+    #[cfg(test)]
+    let secret = "test secret".to_owned();
+    #[cfg(not(test))]
+    let secret = std::env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+    Keys::new(secret.as_bytes())
+});
+
+pub(crate) struct Keys {
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+}
+
+impl Keys {
+    fn new(secret: &[u8]) -> Self {
+        Self {
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Role {
+    User,
+    Admin,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Claims {
+    sub: i32,
+    role: Role,
+    sid: i32,
+    iat: u64,
+    exp: u64,
+    // Set for admins whose roles require TOTP before they have enrolled.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    totp_enrollment: bool,
+}
+
+const TOTP_CHALLENGE_AUDIENCE: &str = "totp-challenge";
+const TOTP_CHALLENGE_TTL_SECS: u64 = 300;
+
+/// Proof that an admin passed the password step of a TOTP login.
+#[derive(Debug, Serialize, Deserialize)]
+struct ChallengeClaims {
+    sub: i32,
+    aud: String,
+    /// Recorded in `admin_totp_challenges` until the challenge is used.
+    jti: String,
+    iat: u64,
+    exp: u64,
+}
+
+/// `Admin` RPCs an admin may call while their token is limited to enrolment.
+const TOTP_ENROLLMENT_METHODS: &[&str] = &["EnrollTotp", "ConfirmTotp"];
+
+/// The authenticated caller, inserted into the request extensions by [`AuthService`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Principal {
+    User(i32),
+    Admin(i32),
+}
+
+/// Id of the session the caller's access token was issued for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SessionId(pub(crate) i32);
+
+pub(crate) fn issue_token(
+    principal: Principal,
+    session_id: i32,
+    totp_enrollment: bool,
+) -> Result<String, tonic::Status> {
+    let (sub, role) = match principal {
+        Principal::User(user_id) => (user_id, Role::User),
+        Principal::Admin(admin_id) => (admin_id, Role::Admin),
+    };
+
+    let iat = jsonwebtoken::get_current_timestamp();
+    let claims = Claims {
+        sub,
+        role,
+        sid: session_id,
+        iat,
+        exp: iat + ACCESS_TOKEN_TTL_SECS,
+        totp_enrollment,
+    };
+
+    jsonwebtoken::encode(&Header::default(), &claims, &KEYS.encoding).map_err(|e| {
+        tracing::error!(error = ?e, "Internal error");
+        tonic::Status::internal("Internal Server Error")
+    })
+}
+
+pub(crate) async fn issue_totp_challenge(
+    db_pool: &sqlx::PgPool,
+    admin_id: i32,
+) -> Result<String, tonic::Status> {
+    let jti = token::generate();
+
+    query!("DELETE FROM admin_totp_challenges WHERE expires_at <= now();")
+        .execute(db_pool)
+        .await
+        .map_err(DbError::from)?;
+
+    query!(
+        "INSERT INTO admin_totp_challenges (challenge_id_hash, admin_id, expires_at) VALUES ($1, $2, now() + make_interval(secs => $3));",
+        token::digest(&jti),
+        admin_id,
+        TOTP_CHALLENGE_TTL_SECS as f64
+    )
+    .execute(db_pool)
+    .await
+    .map_err(DbError::from)?;
+
+    let iat = jsonwebtoken::get_current_timestamp();
+    let claims = ChallengeClaims {
+        sub: admin_id,
+        aud: TOTP_CHALLENGE_AUDIENCE.to_owned(),
+        jti,
+        iat,
+        exp: iat + TOTP_CHALLENGE_TTL_SECS,
+    };
+
+    jsonwebtoken::encode(&Header::default(), &claims, &KEYS.encoding).map_err(|e| {
+        tracing::error!(error = ?e, "Internal error");
+        tonic::Status::internal("Internal Server Error")
+    })
+}
+
+/// Returns the admin a TOTP challenge was issued to, using the challenge up:
+/// a wrong code means logging in with the password again.
+pub(crate) async fn verify_totp_challenge(
+    db_pool: &sqlx::PgPool,
+    challenge: &str,
+) -> Result<i32, tonic::Status> {
+    let mut validation = Validation::default();
+    validation.set_audience(&[TOTP_CHALLENGE_AUDIENCE]);
+
+    let claims = jsonwebtoken::decode::<ChallengeClaims>(challenge, &KEYS.decoding, &validation)
+        .map_err(|e| {
+            tracing::debug!(error = ?e, "Rejected TOTP challenge");
+            tonic::Status::unauthenticated("Invalid TOTP challenge")
+        })?
+        .claims;
+
+    let res = query!(
+        "DELETE FROM admin_totp_challenges WHERE challenge_id_hash = $1 AND admin_id = $2 AND expires_at > now();",
+        token::digest(&claims.jti),
+        claims.sub
+    )
+    .execute(db_pool)
+    .await
+    .map_err(DbError::from)?;
+
+    match res.rows_affected() {
+        1 => Ok(claims.sub),
+        _ => Err(tonic::Status::unauthenticated("Invalid TOTP challenge")),
+    }
+}
+
+fn authenticate(headers: &http::HeaderMap) -> Result<Claims, tonic::Status> {
+    let token = headers
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| tonic::Status::unauthenticated("Missing bearer token"))?;
+
+    let claims = jsonwebtoken::decode::<Claims>(token, &KEYS.decoding, &Validation::default())
+        .map_err(|e| {
+            tracing::debug!(error = ?e, "Rejected bearer token");
+            tonic::Status::unauthenticated("Invalid bearer token")
+        })?
+        .claims;
+
+    Ok(claims)
+}
+
+async fn authorize(
+    db_pool: &sqlx::PgPool,
+    role: Role,
+    request: &mut http::Request<Body>,
+) -> Result<(), tonic::Status> {
+    let claims = authenticate(request.headers())?;
+    let principal = match claims.role {
+        Role::User => Principal::User(claims.sub),
+        Role::Admin => Principal::Admin(claims.sub),
+    };
+    let session_id = SessionId(claims.sid);
+
+    tracing::Span::current().record("principal", tracing::field::debug(principal));
+
+    if !session::is_active(db_pool, principal, session_id.0).await? {
+        return Err(tonic::Status::unauthenticated("Session has been revoked"));
+    }
+
+    match (role, principal) {
+        (Role::User, Principal::User(_)) => {}
+        (Role::Admin, Principal::Admin(admin_id)) => {
+            let method = request.uri().path().rsplit('/').next().unwrap_or_default();
+            if claims.totp_enrollment && !TOTP_ENROLLMENT_METHODS.contains(&method) {
+                return Err(tonic::Status::permission_denied("TOTP enrolment required"));
+            }
+            rbac::authorize(db_pool, admin_id, method).await?;
+        }
+        (Role::User, _) => return Err(tonic::Status::permission_denied("User token required")),
+        (Role::Admin, _) => return Err(tonic::Status::permission_denied("Admin token required")),
+    }
+
+    request.extensions_mut().insert(principal);
+    request.extensions_mut().insert(session_id);
+
+    Ok(())
+}
+
+/// Wraps a generated gRPC server so every call carries a valid token for
+/// `role`, and admin calls hold the permission their RPC requires.
+#[derive(Debug, Clone)]
+pub(crate) struct AuthService<S> {
+    inner: S,
+    db_pool: Arc<sqlx::PgPool>,
+    role: Role,
+}
+
+impl<S> AuthService<S> {
+    pub(crate) fn user(inner: S, db_pool: Arc<sqlx::PgPool>) -> Self {
+        Self {
+            inner,
+            db_pool,
+            role: Role::User,
+        }
+    }
+
+    pub(crate) fn admin(inner: S, db_pool: Arc<sqlx::PgPool>) -> Self {
+        Self {
+            inner,
+            db_pool,
+            role: Role::Admin,
+        }
+    }
+}
+
+impl<S> Service<http::Request<Body>> for AuthService<S>
+where
+    S: Service<http::Request<Body>, Response = http::Response<BoxBody>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: http::Request<Body>) -> Self::Future {
+        // Hand the future the service that was polled ready and keep the clone.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let db_pool = self.db_pool.clone();
+        let role = self.role;
+
+        Box::pin(async move {
+            match authorize(&db_pool, role, &mut request).await {
+                Ok(()) => inner.call(request).await,
+                Err(status) => Ok(status.to_http()),
+            }
+        })
+    }
+}
+
+impl<S: NamedService> NamedService for AuthService<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+pub(crate) fn user_id<T>(request: &tonic::Request<T>) -> Result<i32, tonic::Status> {
+    match request.extensions().get::<Principal>() {
+        Some(Principal::User(user_id)) => Ok(*user_id),
+        _ => Err(tonic::Status::unauthenticated(
+            "Not authenticated as a user",
+        )),
+    }
+}
+
+pub(crate) fn admin_id<T>(request: &tonic::Request<T>) -> Result<i32, tonic::Status> {
+    match request.extensions().get::<Principal>() {
+        Some(Principal::Admin(admin_id)) => Ok(*admin_id),
+        _ => Err(tonic::Status::unauthenticated(
+            "Not authenticated as an admin",
+        )),
+    }
+}
+
+/// Resolves the account a `User` RPC acts on. A zero (unset) `user_id` in the
+/// request body means the caller; any other id must match the caller.
+pub(crate) fn owned_user_id<T>(
+    request: &tonic::Request<T>,
+    requested: i32,
+) -> Result<i32, tonic::Status> {
+    let user_id = user_id(request)?;
+
+    match requested == 0 || requested == user_id {
+        true => Ok(user_id),
+        false => Err(tonic::Status::permission_denied(
+            "Cannot act on another user's account",
+        )),
+    }
+}
+
+pub(crate) fn session_id<T>(request: &tonic::Request<T>) -> Result<i32, tonic::Status> {
+    request
+        .extensions()
+        .get::<SessionId>()
+        .map(|session_id| session_id.0)
+        .ok_or_else(|| tonic::Status::unauthenticated("Not authenticated"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn as_user(user_id: i32) -> tonic::Request<()> {
+        let mut request = tonic::Request::new(());
+        request.extensions_mut().insert(Principal::User(user_id));
+        request
+    }
+
+    #[test]
+    fn users_act_on_their_own_account_by_default() {
+        assert_eq!(owned_user_id(&as_user(7), 0).unwrap(), 7);
+        assert_eq!(owned_user_id(&as_user(7), 7).unwrap(), 7);
+    }
+
+    #[test]
+    fn users_cannot_act_on_another_account() {
+        let status = owned_user_id(&as_user(7), 8).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        assert_eq!(status.message(), "Cannot act on another user's account");
+    }
+
+    #[test]
+    fn admins_are_not_users() {
+        let mut request = tonic::Request::new(());
+        request.extensions_mut().insert(Principal::Admin(7));
+
+        let status = owned_user_id(&request, 7).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let status = owned_user_id(&tonic::Request::new(()), 0).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+}
@@ -0,0 +1,172 @@
+use rand::{distributions::Alphanumeric, Rng};
+use sqlx::{query, query_scalar};
+use std::error::Error;
+
+use crate::password::{self, Verification};
+use crate::{account, rbac};
+
+const GENERATED_PASSWORD_LEN: usize = 24;
+/// Account older versions seeded as admin/admin on startup.
+const SEEDED_ADMIN_ID: i32 = 0;
+const SEEDED_ADMIN_PASSWORD: &str = "admin";
+
+/// Deletes the admin/admin owner older versions seeded, whether its password
+/// is still plaintext or was rehashed on login. Runs on every startup so the
+/// credential never outlives an upgrade.
+pub(crate) async fn remove_seeded_admin(db_pool: &sqlx::PgPool) -> Result<(), Box<dyn Error>> {
+    let mut tx = db_pool.begin().await?;
+
+    let seeded_password = query_scalar!(
+        "SELECT password FROM admins WHERE admin_id = $1 FOR UPDATE;",
+        SEEDED_ADMIN_ID
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(seeded_password) = seeded_password else {
+        return Ok(());
+    };
+
+    if password::verify(SEEDED_ADMIN_PASSWORD, Some(&seeded_password)).await?
+        == Verification::Invalid
+    {
+        return Ok(());
+    }
+
+    query!("DELETE FROM admins WHERE admin_id = $1;", SEEDED_ADMIN_ID)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    tracing::warn!(
+        admin_id = SEEDED_ADMIN_ID,
+        "Removed the seeded admin/admin account"
+    );
+
+    Ok(())
+}
+
+/// Creates the first owner account. Reads `BOOTSTRAP_ADMIN_USERNAME`,
+/// `BOOTSTRAP_ADMIN_EMAIL` and `BOOTSTRAP_ADMIN_PASSWORD`, generating and
+/// printing a password when none is supplied. Refuses to run once an owner exists.
+pub(crate) async fn bootstrap_admin(db_pool: &sqlx::PgPool) -> Result<(), Box<dyn Error>> {
+    let username = std::env::var("BOOTSTRAP_ADMIN_USERNAME").unwrap_or_else(|_| "admin".into());
+    let username = account::normalize_username(&username);
+    let email =
+        account::normalize_email(&std::env::var("BOOTSTRAP_ADMIN_EMAIL").unwrap_or_default());
+    let (admin_password, generated) = match std::env::var("BOOTSTRAP_ADMIN_PASSWORD") {
+        Ok(password) if !password.is_empty() => (password, false),
+        _ => (
+            rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(GENERATED_PASSWORD_LEN)
+                .map(char::from)
+                .collect(),
+            true,
+        ),
+    };
+
+    let mut tx = db_pool.begin().await?;
+
+    // Serialize concurrent bootstraps so only one of them can create the owner.
+    query!("LOCK TABLE admin_roles IN SHARE ROW EXCLUSIVE MODE;")
+        .execute(&mut *tx)
+        .await?;
+
+    let owner_exists = query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM admin_roles WHERE role = $1) AS "exists!";"#,
+        rbac::OWNER_ROLE
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if owner_exists {
+        return Err("An owner account already exists; refusing to bootstrap another".into());
+    }
+
+    let admin_id = query_scalar!(
+        "INSERT INTO admins (username, password, email) VALUES ($1, $2, $3) RETURNING admin_id;",
+        username,
+        password::hash(&admin_password).await?,
+        email
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    query!(
+        "INSERT INTO admin_roles (admin_id, role) VALUES ($1, $2);",
+        admin_id,
+        rbac::OWNER_ROLE
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    println!("Created owner account {} (admin_id {})", username, admin_id);
+
+    if generated {
+        println!("Generated password: {}", admin_password);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    async fn seed(db_pool: &sqlx::PgPool, password: &str) {
+        query!(
+            "INSERT INTO admins (admin_id, username, password, email) VALUES ($1, 'admin', $2, 'admin');",
+            SEEDED_ADMIN_ID,
+            password
+        )
+        .execute(db_pool)
+        .await
+        .unwrap();
+    }
+
+    async fn seed_exists(db_pool: &sqlx::PgPool) -> bool {
+        query_scalar!(
+            r#"SELECT EXISTS (SELECT 1 FROM admins WHERE admin_id = $1) AS "exists!";"#,
+            SEEDED_ADMIN_ID
+        )
+        .fetch_one(db_pool)
+        .await
+        .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn removes_the_plaintext_seed(db_pool: sqlx::PgPool) {
+        seed(&db_pool, SEEDED_ADMIN_PASSWORD).await;
+
+        remove_seeded_admin(&db_pool).await.unwrap();
+
+        assert!(!seed_exists(&db_pool).await);
+    }
+
+    #[sqlx::test]
+    async fn removes_the_seed_once_rehashed(db_pool: sqlx::PgPool) {
+        seed(
+            &db_pool,
+            &password::hash(SEEDED_ADMIN_PASSWORD).await.unwrap(),
+        )
+        .await;
+
+        remove_seeded_admin(&db_pool).await.unwrap();
+
+        assert!(!seed_exists(&db_pool).await);
+    }
+
+    #[sqlx::test]
+    async fn keeps_the_seed_once_its_password_changed(db_pool: sqlx::PgPool) {
+        seed(&db_pool, fixtures::PASSWORD_HASH).await;
+
+        remove_seeded_admin(&db_pool).await.unwrap();
+
+        assert!(seed_exists(&db_pool).await);
+    }
+}
@@ -0,0 +1,127 @@
+use rust_decimal::Decimal;
+use sqlx::{query_as, query_scalar, PgExecutor};
+use tonic::Code;
+
+use crate::error::{self, DbError};
+use crate::{money, proto};
+
+/// A cart line at its product's current price.
+#[derive(Debug)]
+pub(crate) struct Line {
+    pub(crate) product_id: i32,
+    pub(crate) quantity: i32,
+    pub(crate) unit_price: Decimal,
+    pub(crate) currency_code: String,
+    pub(crate) name: String,
+}
+
+impl Line {
+    pub(crate) fn to_proto(&self) -> proto::LineItem {
+        proto::LineItem {
+            product_id: self.product_id,
+            quantity: self.quantity,
+            unit_price: Some(money::to_proto(self.unit_price, &self.currency_code)),
+            name: self.name.clone(),
+        }
+    }
+}
+
+/// The user's cart at current prices.
+pub(crate) async fn items<'e, E: PgExecutor<'e>>(
+    executor: E,
+    user_id: i32,
+) -> Result<Vec<Line>, tonic::Status> {
+    query_as!(
+        Line,
+        r#"SELECT cart_items.product_id, cart_items.quantity, products.price AS unit_price, products.currency_code, products.name
+            FROM cart_items
+            JOIN products ON products.product_id = cart_items.product_id
+            WHERE cart_items.user_id = $1
+            ORDER BY cart_items.product_id;"#,
+        user_id
+    )
+    .fetch_all(executor)
+    .await
+    .map_err(|e| DbError::from(e).into())
+}
+
+/// The amount and currency due for `lines`, or `None` if there are none or
+/// they aren't all in one currency.
+pub(crate) fn total(lines: &[Line]) -> Option<(Decimal, &str)> {
+    let currency_code = &lines.first()?.currency_code;
+    if lines
+        .iter()
+        .any(|line| &line.currency_code != currency_code)
+    {
+        return None;
+    }
+
+    let total = lines
+        .iter()
+        .map(|line| line.unit_price * Decimal::from(line.quantity))
+        .sum();
+
+    Some((total, currency_code))
+}
+
+/// Fails unless everything already in the cart is priced in `currency_code`.
+pub(crate) async fn check_currency<'e, E: PgExecutor<'e>>(
+    executor: E,
+    user_id: i32,
+    currency_code: &str,
+) -> Result<(), tonic::Status> {
+    let other = query_scalar!(
+        "SELECT products.currency_code FROM cart_items JOIN products ON products.product_id = cart_items.product_id WHERE cart_items.user_id = $1 AND products.currency_code <> $2 LIMIT 1;",
+        user_id,
+        currency_code
+    )
+    .fetch_optional(executor)
+    .await
+    .map_err(DbError::from)?;
+
+    match other {
+        Some(other) => Err(mixed_currencies(&[other.as_str(), currency_code])),
+        None => Ok(()),
+    }
+}
+
+pub(crate) fn mixed_currencies(currency_codes: &[&str]) -> tonic::Status {
+    error::with_details(
+        Code::FailedPrecondition,
+        "Cart items must all be in one currency",
+        vec![error::error_info(
+            "MIXED_CURRENCIES",
+            &[("currency_codes", &currency_codes.join(","))],
+        )],
+    )
+}
+
+/// Locks the user and their cart lines for the rest of `tx` and returns the
+/// cart, so concurrent cart updates and checkouts wait for it to finish.
+pub(crate) async fn lock(
+    tx: &mut sqlx::PgConnection,
+    user_id: i32,
+) -> Result<Vec<Line>, tonic::Status> {
+    query_scalar!(
+        "SELECT user_id FROM users WHERE user_id = $1 FOR UPDATE;",
+        user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(DbError::from)?
+    .ok_or_else(|| tonic::Status::not_found("User not found"))?;
+
+    query_as!(
+        Line,
+        r#"SELECT cart_items.product_id, cart_items.quantity, products.price AS unit_price, products.currency_code, products.name
+            FROM cart_items
+            JOIN products ON products.product_id = cart_items.product_id
+            WHERE cart_items.user_id = $1
+            ORDER BY cart_items.product_id
+            FOR UPDATE OF cart_items;"#,
+        user_id
+    )
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(|e| DbError::from(e).into())
+}
@@ -0,0 +1,206 @@
+use prost::Message;
+use sqlx::{error::ErrorKind, postgres::PgDatabaseError};
+use tonic::Code;
+
+use crate::google::rpc;
+
+const DOMAIN: &str = "rust_ecom";
+
+/// Request field each named constraint guards, so violations can point at it.
+const CONSTRAINT_FIELDS: &[(&str, &str)] = &[
+    ("admin_roles_admin_id_fkey", "admin_id"),
+    ("admin_roles_role_fkey", "role"),
+    ("admins_email_lower_key", "email"),
+    ("admins_username_lower_key", "username"),
+    ("cart_items_product_id_fkey", "product_id"),
+    ("inventory_available_check", "delta"),
+    ("inventory_movements_append_only", "movement_id"),
+    ("inventory_product_id_fkey", "product_id"),
+    ("inventory_stock_on_hand_check", "delta"),
+    ("order_items_product_id_fkey", "product_id"),
+    ("users_email_lower_key", "email"),
+    ("users_username_lower_key", "username"),
+];
+
+fn constraint_field(constraint: &str) -> Option<&'static str> {
+    CONSTRAINT_FIELDS
+        .iter()
+        .find(|(name, _)| *name == constraint)
+        .map(|(_, field)| *field)
+}
+
+fn pack(type_name: &str, message: &impl Message) -> prost_types::Any {
+    prost_types::Any {
+        type_url: format!("type.googleapis.com/google.rpc.{}", type_name),
+        value: message.encode_to_vec(),
+    }
+}
+
+/// Builds a status whose details carry a `google.rpc.Status` with `details`.
+pub(crate) fn with_details(
+    code: Code,
+    message: impl Into<String>,
+    details: Vec<prost_types::Any>,
+) -> tonic::Status {
+    let message = message.into();
+    let status = rpc::Status {
+        code: code as i32,
+        message: message.clone(),
+        details,
+    };
+
+    tonic::Status::with_details(code, message, status.encode_to_vec().into())
+}
+
+pub(crate) fn error_info(reason: &str, metadata: &[(&str, &str)]) -> prost_types::Any {
+    pack(
+        "ErrorInfo",
+        &rpc::ErrorInfo {
+            reason: reason.to_owned(),
+            domain: DOMAIN.to_owned(),
+            metadata: metadata
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        },
+    )
+}
+
+pub(crate) fn bad_request(violations: &[(&str, &str)]) -> prost_types::Any {
+    pack(
+        "BadRequest",
+        &rpc::BadRequest {
+            field_violations: violations
+                .iter()
+                .map(|(field, description)| rpc::bad_request::FieldViolation {
+                    field: field.to_string(),
+                    description: description.to_string(),
+                })
+                .collect(),
+        },
+    )
+}
+
+/// `(type, subject, description)` for each failed precondition.
+pub(crate) fn precondition_failure(violations: &[(&str, &str, &str)]) -> prost_types::Any {
+    pack(
+        "PreconditionFailure",
+        &rpc::PreconditionFailure {
+            violations: violations
+                .iter()
+                .map(
+                    |(kind, subject, description)| rpc::precondition_failure::Violation {
+                        r#type: kind.to_string(),
+                        subject: subject.to_string(),
+                        description: description.to_string(),
+                    },
+                )
+                .collect(),
+        },
+    )
+}
+
+/// ABORTED for a write conditioned on a version the row has since moved past.
+pub(crate) fn version_mismatch(current_version: i32) -> tonic::Status {
+    with_details(
+        Code::Aborted,
+        "Changed since it was read, re-read it and retry",
+        vec![error_info(
+            "VERSION_MISMATCH",
+            &[("current_version", &current_version.to_string())],
+        )],
+    )
+}
+
+/// A failed query. Converting it into a [`tonic::Status`] picks the closest
+/// gRPC code, attaches `google.rpc` details for constraint violations and
+/// only logs what the client can't fix.
+#[derive(Debug)]
+pub(crate) struct DbError(sqlx::Error);
+
+impl From<sqlx::Error> for DbError {
+    fn from(e: sqlx::Error) -> Self {
+        Self(e)
+    }
+}
+
+impl From<DbError> for tonic::Status {
+    fn from(DbError(e): DbError) -> Self {
+        match &e {
+            sqlx::Error::RowNotFound => tonic::Status::not_found("Not found"),
+            sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed | sqlx::Error::Io(_) => {
+                tracing::warn!(error = ?e, "Database unavailable");
+                tonic::Status::unavailable("Database unavailable")
+            }
+            // serialization_failure, deadlock_detected
+            sqlx::Error::Database(db)
+                if matches!(db.code().as_deref(), Some("40001" | "40P01")) =>
+            {
+                tracing::debug!(error = ?e, "Transaction conflict");
+                tonic::Status::aborted("Conflicting concurrent update, please retry")
+            }
+            sqlx::Error::Database(db) if db.kind() != ErrorKind::Other => {
+                tracing::debug!(error = ?e, "Constraint violation");
+
+                let constraint = db.constraint().unwrap_or_default();
+                let field = constraint_field(constraint);
+
+                match db.kind() {
+                    ErrorKind::UniqueViolation => {
+                        let mut details = vec![error_info(
+                            "UNIQUE_VIOLATION",
+                            &[("constraint", constraint)],
+                        )];
+                        let message = match field {
+                            Some(field) => {
+                                details.push(bad_request(&[(field, "Already taken")]));
+                                format!("{} is already taken", field)
+                            }
+                            None => "Already exists".to_owned(),
+                        };
+
+                        with_details(Code::AlreadyExists, message, details)
+                    }
+                    ErrorKind::NotNullViolation => {
+                        let column = db
+                            .try_downcast_ref::<PgDatabaseError>()
+                            .and_then(PgDatabaseError::column)
+                            .unwrap_or_default();
+
+                        with_details(
+                            Code::InvalidArgument,
+                            format!("{} is required", column),
+                            vec![bad_request(&[(column, "Required")])],
+                        )
+                    }
+                    kind => {
+                        let (kind, message) = match kind {
+                            ErrorKind::ForeignKeyViolation => (
+                                "FOREIGN_KEY",
+                                "Refers to a missing or still referenced record",
+                            ),
+                            _ => ("CHECK", "Violates a data constraint"),
+                        };
+                        let subject = field.unwrap_or(constraint);
+
+                        with_details(
+                            Code::FailedPrecondition,
+                            message,
+                            vec![
+                                error_info(
+                                    &format!("{}_VIOLATION", kind),
+                                    &[("constraint", constraint)],
+                                ),
+                                precondition_failure(&[(kind, subject, message)]),
+                            ],
+                        )
+                    }
+                }
+            }
+            _ => {
+                tracing::error!(error = ?e, "Internal error");
+                tonic::Status::internal("Internal Server Error")
+            }
+        }
+    }
+}
@@ -0,0 +1,45 @@
+//! Rows for database tests, each of which runs against a fresh database.
+
+use sqlx::query_scalar;
+
+/// Password of every fixture account.
+pub(crate) const PASSWORD: &str = "correct horse battery";
+
+/// Argon2 hash of [`PASSWORD`] with the default parameters, so tests don't
+/// pay for hashing it.
+pub(crate) const PASSWORD_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$Fxc4Ye1bIwuaWf79Sl5QaQ$fsZH/aCcNYGe676FAJUMzF40udfyfZw2aHhhMbodloM";
+
+pub(crate) async fn admin(db_pool: &sqlx::PgPool, username: &str) -> i32 {
+    query_scalar!(
+        "INSERT INTO admins (username, password, email) VALUES ($1, $2, $3) RETURNING admin_id;",
+        username,
+        PASSWORD_HASH,
+        format!("{}@example.com", username)
+    )
+    .fetch_one(db_pool)
+    .await
+    .unwrap()
+}
+
+pub(crate) async fn user(db_pool: &sqlx::PgPool, username: &str) -> i32 {
+    query_scalar!(
+        "INSERT INTO users (username, password, email, orders) VALUES ($1, $2, $3, '{}') RETURNING user_id;",
+        username,
+        PASSWORD_HASH,
+        format!("{}@example.com", username)
+    )
+    .fetch_one(db_pool)
+    .await
+    .unwrap()
+}
+
+/// A product priced at 2 USD.
+pub(crate) async fn product(db_pool: &sqlx::PgPool, name: &str) -> i32 {
+    query_scalar!(
+        "INSERT INTO products (name, description, price, currency_code) VALUES ($1, '', 2, 'USD') RETURNING product_id;",
+        name
+    )
+    .fetch_one(db_pool)
+    .await
+    .unwrap()
+}
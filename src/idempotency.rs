@@ -0,0 +1,570 @@
+use data_encoding::HEXLOWER;
+use hyper::body::Bytes;
+use sha2::{Digest, Sha256};
+use sqlx::types::time::OffsetDateTime;
+use sqlx::{query, query_as};
+use std::{
+    convert::Infallible,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tonic::{
+    body::BoxBody,
+    codegen::{http, BoxFuture, Service},
+    server::NamedService,
+    transport::Body,
+};
+
+use crate::auth::Principal;
+use crate::error::DbError;
+
+pub(crate) const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+const MAX_KEY_LEN: usize = 255;
+
+/// How long a completed result is replayed for.
+const KEY_TTL_SECS: f64 = 60.0 * 60.0 * 24.0;
+
+/// How long an unfinished first attempt holds its key without a heartbeat
+/// before a retry may take it over. Attempts that are still running refresh
+/// their claim every [`HEARTBEAT_SECS`], so only crashed ones expire.
+const PENDING_TTL_SECS: f64 = 60.0;
+
+const HEARTBEAT_SECS: f64 = PENDING_TTL_SECS / 3.0;
+
+/// RPCs that honour `idempotency-key`, in any service. Logins, token refresh
+/// and TOTP enrolment are left out so no credentials are ever stored.
+const IDEMPOTENT_METHODS: &[&str] = &[
+    // Storefront
+    "CreateUserAccount",
+    "RequestPasswordReset",
+    "ConfirmPasswordReset",
+    "VerifyEmail",
+    // Admin
+    "CreateProduct",
+    "UpdateProduct",
+    "DeleteProduct",
+    "AdjustStock",
+    "UpdateOrder",
+    "TransitionOrder",
+    "DeleteOrder",
+    "CreateAdminAccount",
+    "UpdateAdminAccount",
+    "DeleteAdminAccount",
+    "AssignAdminRole",
+    "RevokeAdminRole",
+    "DisableTotp",
+    "DeleteUserAccount",
+    "RevokeSession",
+    // User
+    "UpdateUserAccount",
+    "AddProductToCart",
+    "RemoveProductFromCart",
+    "Checkout",
+];
+
+/// Keys are per caller. Anonymous callers can't be told apart, so theirs are
+/// also per request: only a retry of the very same request shares its key.
+fn scope(principal: Option<Principal>, fingerprint: &[u8]) -> String {
+    match principal {
+        Some(Principal::User(user_id)) => format!("user:{}", user_id),
+        Some(Principal::Admin(admin_id)) => format!("admin:{}", admin_id),
+        None => format!("anonymous:{}", HEXLOWER.encode(fingerprint)),
+    }
+}
+
+fn key(headers: &http::HeaderMap) -> Result<Option<String>, tonic::Status> {
+    let Some(value) = headers.get(IDEMPOTENCY_KEY_HEADER) else {
+        return Ok(None);
+    };
+
+    match value.to_str() {
+        Ok(key)
+            if !key.is_empty()
+                && key.len() <= MAX_KEY_LEN
+                && key.bytes().all(|b| b.is_ascii_graphic()) =>
+        {
+            Ok(Some(key.to_owned()))
+        }
+        _ => Err(tonic::Status::invalid_argument(format!(
+            "{} must be 1 to {} visible ASCII characters",
+            IDEMPOTENCY_KEY_HEADER, MAX_KEY_LEN
+        ))),
+    }
+}
+
+#[derive(Debug)]
+struct StoredKey {
+    fingerprint: Vec<u8>,
+    response: Option<Vec<u8>>,
+    created_at: OffsetDateTime,
+}
+
+enum Claim {
+    /// The key is ours; run the RPC and record its result.
+    Acquired,
+    /// The key already completed this request.
+    Replay(Vec<u8>),
+}
+
+/// Claims `key` for a request with `fingerprint`, or finds its earlier result.
+async fn claim(
+    db_pool: &sqlx::PgPool,
+    scope: &str,
+    key: &str,
+    fingerprint: &[u8],
+) -> Result<Claim, tonic::Status> {
+    // Forget every expired result, and this key's abandoned attempt so it can
+    // be claimed.
+    query!(
+        r#"DELETE FROM idempotency_keys
+            WHERE created_at < now() - make_interval(secs => $3)
+                OR (scope = $1 AND key = $2 AND response IS NULL
+                    AND created_at < now() - make_interval(secs => $4));"#,
+        scope,
+        key,
+        KEY_TTL_SECS,
+        PENDING_TTL_SECS
+    )
+    .execute(db_pool)
+    .await
+    .map_err(DbError::from)?;
+
+    let acquired = query!(
+        "INSERT INTO idempotency_keys (scope, key, fingerprint) VALUES ($1, $2, $3) ON CONFLICT (scope, key) DO NOTHING;",
+        scope,
+        key,
+        fingerprint
+    )
+    .execute(db_pool)
+    .await
+    .map_err(DbError::from)?
+    .rows_affected()
+        == 1;
+
+    if acquired {
+        return Ok(Claim::Acquired);
+    }
+
+    let stored = query_as!(
+        StoredKey,
+        "SELECT fingerprint, response, created_at FROM idempotency_keys WHERE scope = $1 AND key = $2;",
+        scope,
+        key
+    )
+    .fetch_optional(db_pool)
+    .await
+    .map_err(DbError::from)?
+    .ok_or_else(|| tonic::Status::aborted("Idempotency key was just released, please retry"))?;
+
+    tracing::debug!(
+        created_at = %stored.created_at,
+        "Idempotency key already used"
+    );
+
+    match (stored.fingerprint == fingerprint, stored.response) {
+        (false, _) => Err(tonic::Status::already_exists(
+            "Idempotency key was already used for a different request",
+        )),
+        (true, None) => Err(tonic::Status::aborted(
+            "A request with this idempotency key is still in progress",
+        )),
+        (true, Some(response)) => Ok(Claim::Replay(response)),
+    }
+}
+
+/// Keeps a running attempt's claim from looking abandoned.
+async fn heartbeat(db_pool: &sqlx::PgPool, scope: &str, key: &str) -> Result<(), tonic::Status> {
+    query!(
+        "UPDATE idempotency_keys SET created_at = now() WHERE scope = $1 AND key = $2 AND response IS NULL;",
+        scope,
+        key
+    )
+    .execute(db_pool)
+    .await
+    .map_err(DbError::from)?;
+
+    Ok(())
+}
+
+async fn complete(
+    db_pool: &sqlx::PgPool,
+    scope: &str,
+    key: &str,
+    response: Option<&[u8]>,
+) -> Result<(), tonic::Status> {
+    // Failed calls release the key so the request can be retried.
+    match response {
+        Some(response) => query!(
+            "UPDATE idempotency_keys SET response = $3 WHERE scope = $1 AND key = $2;",
+            scope,
+            key,
+            response
+        )
+        .execute(db_pool)
+        .await
+        .map_err(DbError::from)?,
+        None => query!(
+            "DELETE FROM idempotency_keys WHERE scope = $1 AND key = $2;",
+            scope,
+            key
+        )
+        .execute(db_pool)
+        .await
+        .map_err(DbError::from)?,
+    };
+
+    Ok(())
+}
+
+/// A unary gRPC response body held in memory: its message frame(s), then the
+/// trailers.
+struct Buffered {
+    data: Option<Bytes>,
+    trailers: Option<http::HeaderMap>,
+}
+
+impl Buffered {
+    fn ok(data: Bytes) -> Self {
+        let mut trailers = http::HeaderMap::new();
+        trailers.insert("grpc-status", http::HeaderValue::from_static("0"));
+
+        Self {
+            data: Some(data),
+            trailers: Some(trailers),
+        }
+    }
+}
+
+impl http_body::Body for Buffered {
+    type Data = Bytes;
+    type Error = tonic::Status;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Poll::Ready(self.data.take().map(Ok))
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+        Poll::Ready(Ok(self.trailers.take()))
+    }
+}
+
+fn replay(response: Vec<u8>) -> http::Response<BoxBody> {
+    let mut replay = http::Response::new(BoxBody::new(Buffered::ok(response.into())));
+    let headers = replay.headers_mut();
+    headers.insert(
+        http::header::CONTENT_TYPE,
+        http::HeaderValue::from_static("application/grpc"),
+    );
+    headers.insert(
+        "idempotent-replayed",
+        http::HeaderValue::from_static("true"),
+    );
+
+    replay
+}
+
+/// Runs `request` once for its key and records the encoded reply if it
+/// succeeds. Errors are passed through and release the key.
+async fn run<S>(
+    inner: &mut S,
+    db_pool: &sqlx::PgPool,
+    principal: Option<Principal>,
+    key: String,
+    request: http::Request<Body>,
+) -> Result<http::Response<BoxBody>, tonic::Status>
+where
+    S: Service<http::Request<Body>, Response = http::Response<BoxBody>, Error = Infallible>,
+{
+    let (parts, body) = request.into_parts();
+    let body = hyper::body::to_bytes(body)
+        .await
+        .map_err(|e| tonic::Status::from_error(Box::new(e)))?;
+
+    let fingerprint = Sha256::new()
+        .chain_update(parts.uri.path())
+        .chain_update(&body)
+        .finalize();
+    let scope = scope(principal, &fingerprint);
+
+    if let Claim::Replay(response) = claim(db_pool, &scope, &key, &fingerprint).await? {
+        tracing::info!("Replaying idempotent response");
+        return Ok(replay(response));
+    }
+
+    let request = http::Request::from_parts(parts, Body::from(body));
+    let call = inner.call(request);
+    tokio::pin!(call);
+
+    let mut heartbeats = tokio::time::interval(Duration::from_secs_f64(HEARTBEAT_SECS));
+    heartbeats.tick().await;
+
+    let response = loop {
+        tokio::select! {
+            response = &mut call => break response.unwrap_or_else(|e| match e {}),
+            _ = heartbeats.tick() => {
+                // Failing to refresh the claim must not abort the call itself.
+                if let Err(status) = heartbeat(db_pool, &scope, &key).await {
+                    tracing::warn!(error = ?status, "Failed to refresh idempotency key");
+                }
+            }
+        }
+    };
+
+    // Unary errors come back trailers-only, with the status in the headers.
+    if response.headers().contains_key("grpc-status") {
+        complete(db_pool, &scope, &key, None).await?;
+        return Ok(response);
+    }
+
+    let (parts, mut body) = response.into_parts();
+    let data = hyper::body::to_bytes(&mut body).await?;
+    let trailers = http_body::Body::trailers(&mut body).await?;
+
+    let succeeded = trailers
+        .as_ref()
+        .and_then(|trailers| trailers.get("grpc-status"))
+        .is_some_and(|status| status == "0");
+    complete(db_pool, &scope, &key, succeeded.then_some(&data[..])).await?;
+
+    let body = Buffered {
+        data: Some(data),
+        trailers,
+    };
+
+    Ok(http::Response::from_parts(parts, BoxBody::new(body)))
+}
+
+/// Wraps a generated gRPC server so that retrying an [`IDEMPOTENT_METHODS`]
+/// call with the same `idempotency-key` returns the first successful reply
+/// instead of running it again. Keys are scoped to the caller, so it goes
+/// inside [`crate::auth::AuthService`] where there is one.
+#[derive(Debug, Clone)]
+pub(crate) struct IdempotencyService<S> {
+    inner: S,
+    db_pool: Arc<sqlx::PgPool>,
+}
+
+impl<S> IdempotencyService<S> {
+    pub(crate) fn new(inner: S, db_pool: Arc<sqlx::PgPool>) -> Self {
+        Self { inner, db_pool }
+    }
+}
+
+impl<S> Service<http::Request<Body>> for IdempotencyService<S>
+where
+    S: Service<http::Request<Body>, Response = http::Response<BoxBody>, Error = Infallible>
+        + Clone
+        + Send
+        + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+        // Hand the future the service that was polled ready and keep the clone.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let db_pool = self.db_pool.clone();
+
+        let method = request.uri().path().rsplit('/').next().unwrap_or_default();
+        let principal = request.extensions().get::<Principal>().copied();
+        let key = match IDEMPOTENT_METHODS.contains(&method) {
+            true => key(request.headers()),
+            false => Ok(None),
+        };
+
+        Box::pin(async move {
+            match key {
+                Ok(Some(key)) => match run(&mut inner, &db_pool, principal, key, request).await {
+                    Ok(response) => Ok(response),
+                    Err(status) => Ok(status.to_http()),
+                },
+                Ok(None) => inner.call(request).await,
+                Err(status) => Ok(status.to_http()),
+            }
+        })
+    }
+}
+
+impl<S: NamedService> NamedService for IdempotencyService<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::sync::Notify;
+    use tonic::Code;
+    use tower::ServiceExt;
+
+    type Inner =
+        tower::util::BoxCloneService<http::Request<Body>, http::Response<BoxBody>, Infallible>;
+
+    /// Answers every call with the number of calls it has seen so far.
+    fn counter() -> (Inner, Arc<AtomicUsize>) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let inner = {
+            let calls = calls.clone();
+            tower::service_fn(move |_request| {
+                let call = calls.fetch_add(1, Ordering::SeqCst) + 1;
+                async move { Ok(response(call)) }
+            })
+        };
+
+        (Inner::new(inner), calls)
+    }
+
+    fn response(call: usize) -> http::Response<BoxBody> {
+        let body = Buffered::ok(Bytes::from(call.to_string()));
+        let mut response = http::Response::new(BoxBody::new(body));
+        response.headers_mut().insert(
+            http::header::CONTENT_TYPE,
+            http::HeaderValue::from_static("application/grpc"),
+        );
+
+        response
+    }
+
+    fn request(principal: Option<Principal>, key: &str, body: &'static str) -> http::Request<Body> {
+        let mut request = http::Request::builder()
+            .uri("/rust_ecom.Admin/CreateProduct")
+            .header(IDEMPOTENCY_KEY_HEADER, key)
+            .body(Body::from(body))
+            .unwrap();
+        if let Some(principal) = principal {
+            request.extensions_mut().insert(principal);
+        }
+
+        request
+    }
+
+    /// The reply's body, or its status if the call failed.
+    async fn outcome(response: http::Response<BoxBody>) -> Result<(String, bool), tonic::Status> {
+        if let Some(status) = tonic::Status::from_header_map(response.headers()) {
+            return Err(status);
+        }
+
+        let replayed = response.headers().contains_key("idempotent-replayed");
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        Ok((String::from_utf8(body.to_vec()).unwrap(), replayed))
+    }
+
+    async fn call(
+        service: &IdempotencyService<Inner>,
+        request: http::Request<Body>,
+    ) -> Result<(String, bool), tonic::Status> {
+        outcome(service.clone().oneshot(request).await.unwrap()).await
+    }
+
+    const ADMIN: Option<Principal> = Some(Principal::Admin(1));
+
+    #[sqlx::test]
+    async fn retries_replay_the_first_response(db_pool: sqlx::PgPool) {
+        let (inner, calls) = counter();
+        let service = IdempotencyService::new(inner, Arc::new(db_pool));
+
+        let first = call(&service, request(ADMIN, "key", "widget")).await;
+        let retry = call(&service, request(ADMIN, "key", "widget")).await;
+
+        assert_eq!(first.unwrap(), ("1".to_owned(), false));
+        assert_eq!(retry.unwrap(), ("1".to_owned(), true));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[sqlx::test]
+    async fn keys_cannot_be_reused_for_other_requests(db_pool: sqlx::PgPool) {
+        let (inner, calls) = counter();
+        let service = IdempotencyService::new(inner, Arc::new(db_pool));
+
+        call(&service, request(ADMIN, "key", "widget"))
+            .await
+            .unwrap();
+        let status = call(&service, request(ADMIN, "key", "gadget"))
+            .await
+            .unwrap_err();
+
+        assert_eq!(status.code(), Code::AlreadyExists);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[sqlx::test]
+    async fn retries_of_a_running_call_are_aborted(db_pool: sqlx::PgPool) {
+        let started = Arc::new(Notify::new());
+        let release = Arc::new(Notify::new());
+        let inner = {
+            let (started, release) = (started.clone(), release.clone());
+            tower::service_fn(move |_request| {
+                let (started, release) = (started.clone(), release.clone());
+                async move {
+                    started.notify_one();
+                    release.notified().await;
+                    Ok(response(1))
+                }
+            })
+        };
+        let service = IdempotencyService::new(Inner::new(inner), Arc::new(db_pool));
+
+        let first = tokio::spawn(service.clone().oneshot(request(ADMIN, "key", "widget")));
+        started.notified().await;
+
+        let status = call(&service, request(ADMIN, "key", "widget"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Aborted);
+
+        release.notify_one();
+        let first = outcome(first.await.unwrap().unwrap()).await;
+        assert_eq!(first.unwrap(), ("1".to_owned(), false));
+    }
+
+    #[sqlx::test]
+    async fn keys_are_scoped_to_the_caller(db_pool: sqlx::PgPool) {
+        let (inner, calls) = counter();
+        let service = IdempotencyService::new(inner, Arc::new(db_pool));
+
+        let admin = call(&service, request(ADMIN, "key", "widget")).await;
+        let other_admin = call(
+            &service,
+            request(Some(Principal::Admin(2)), "key", "widget"),
+        )
+        .await;
+        let user = call(&service, request(Some(Principal::User(1)), "key", "gadget")).await;
+
+        assert_eq!(admin.unwrap(), ("1".to_owned(), false));
+        assert_eq!(other_admin.unwrap(), ("2".to_owned(), false));
+        assert_eq!(user.unwrap(), ("3".to_owned(), false));
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[sqlx::test]
+    async fn anonymous_keys_only_match_the_same_request(db_pool: sqlx::PgPool) {
+        let (inner, calls) = counter();
+        let service = IdempotencyService::new(inner, Arc::new(db_pool));
+
+        let first = call(&service, request(None, "key", "widget")).await;
+        let retry = call(&service, request(None, "key", "widget")).await;
+        let other = call(&service, request(None, "key", "gadget")).await;
+
+        assert_eq!(first.unwrap(), ("1".to_owned(), false));
+        assert_eq!(retry.unwrap(), ("1".to_owned(), true));
+        assert_eq!(other.unwrap(), ("2".to_owned(), false));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,556 @@
+use sqlx::types::time::OffsetDateTime;
+use sqlx::{query, query_as, query_scalar, PgExecutor};
+use tonic::Code;
+
+use crate::error::{self, DbError};
+use crate::page::{self, Condition, Field, Kind, Listing, Op, Page, Value};
+use crate::proto::{self, MovementKind, OrderStatus};
+use crate::timestamp;
+
+/// The `inventory_movements.kind` value for `kind`, e.g. `receipt`.
+fn kind_name(kind: MovementKind) -> String {
+    kind.as_str_name()
+        .trim_start_matches("MOVEMENT_KIND_")
+        .to_lowercase()
+}
+
+/// The `inventory_movements.kind` value for a kind named in a filter.
+fn filter_kind(name: &str) -> Option<String> {
+    match parse_kind(name) {
+        MovementKind::Unspecified => None,
+        kind => Some(kind_name(kind)),
+    }
+}
+
+fn parse_kind(name: &str) -> MovementKind {
+    MovementKind::from_str_name(&format!("MOVEMENT_KIND_{}", name.to_uppercase()))
+        .unwrap_or_default()
+}
+
+/// Reserves stock for every item of a new order, or fails with
+/// `FAILED_PRECONDITION` listing each item that is short.
+pub(crate) async fn reserve(
+    tx: &mut sqlx::PgConnection,
+    order_id: i32,
+    items: &[proto::LineItem],
+) -> Result<(), tonic::Status> {
+    let product_ids: Vec<i32> = items.iter().map(|item| item.product_id).collect();
+    let quantities: Vec<i32> = items.iter().map(|item| item.quantity).collect();
+
+    let reserved = query_scalar!(
+        "UPDATE inventory SET reserved = inventory.reserved + wanted.quantity FROM UNNEST($1::INT[], $2::INT[]) AS wanted (product_id, quantity) WHERE inventory.product_id = wanted.product_id AND inventory.stock_on_hand - inventory.reserved >= wanted.quantity RETURNING inventory.product_id;",
+        &product_ids,
+        &quantities
+    )
+    .fetch_all(&mut *tx)
+    .await
+    .map_err(DbError::from)?;
+
+    if reserved.len() < items.len() {
+        return Err(insufficient_stock(&mut *tx, items, &reserved).await);
+    }
+
+    query!(
+        "WITH reservations AS (INSERT INTO stock_reservations (order_id, product_id, quantity) SELECT $1, * FROM UNNEST($2::INT[], $3::INT[]) RETURNING product_id, quantity) INSERT INTO inventory_movements (product_id, kind, on_hand_change, reserved_change, order_id) SELECT product_id, $4, 0, quantity, $1 FROM reservations;",
+        order_id,
+        &product_ids,
+        &quantities,
+        kind_name(MovementKind::Reservation)
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(DbError::from)?;
+
+    Ok(())
+}
+
+async fn insufficient_stock(
+    tx: &mut sqlx::PgConnection,
+    items: &[proto::LineItem],
+    reserved: &[i32],
+) -> tonic::Status {
+    let short: Vec<&proto::LineItem> = items
+        .iter()
+        .filter(|item| !reserved.contains(&item.product_id))
+        .collect();
+    let product_ids: Vec<i32> = short.iter().map(|item| item.product_id).collect();
+
+    let available = match query!(
+        r#"SELECT product_id, stock_on_hand - reserved AS "available!" FROM inventory WHERE product_id = ANY($1);"#,
+        &product_ids
+    )
+    .fetch_all(&mut *tx)
+    .await
+    {
+        Ok(available) => available,
+        Err(e) => return DbError::from(e).into(),
+    };
+
+    let violations: Vec<(String, String)> = short
+        .iter()
+        .map(|item| {
+            let description = match available
+                .iter()
+                .find(|row| row.product_id == item.product_id)
+            {
+                Some(row) => format!(
+                    "{} requested, {} available",
+                    item.quantity,
+                    row.available.max(0)
+                ),
+                None => format!("{} requested, stock is not tracked", item.quantity),
+            };
+
+            (format!("products/{}", item.product_id), description)
+        })
+        .collect();
+
+    error::with_details(
+        Code::FailedPrecondition,
+        "Insufficient stock",
+        vec![
+            error::error_info("INSUFFICIENT_STOCK", &[]),
+            error::precondition_failure(
+                &violations
+                    .iter()
+                    .map(|(subject, description)| ("STOCK", subject.as_str(), description.as_str()))
+                    .collect::<Vec<_>>(),
+            ),
+        ],
+    )
+}
+
+/// Returns an order's reserved stock to the available pool.
+pub(crate) async fn release(
+    tx: &mut sqlx::PgConnection,
+    order_id: i32,
+) -> Result<(), tonic::Status> {
+    query!(
+        "WITH released AS (DELETE FROM stock_reservations WHERE order_id = $1 RETURNING product_id, quantity), updated AS (UPDATE inventory SET reserved = inventory.reserved - released.quantity FROM released WHERE inventory.product_id = released.product_id) INSERT INTO inventory_movements (product_id, kind, on_hand_change, reserved_change, order_id) SELECT product_id, $2, 0, -quantity, $1 FROM released;",
+        order_id,
+        kind_name(MovementKind::Release)
+    )
+    .execute(tx)
+    .await
+    .map_err(DbError::from)?;
+
+    Ok(())
+}
+
+/// Takes an order's reserved stock off the shelf.
+async fn fulfil(tx: &mut sqlx::PgConnection, order_id: i32) -> Result<(), tonic::Status> {
+    query!(
+        "WITH fulfilled AS (DELETE FROM stock_reservations WHERE order_id = $1 RETURNING product_id, quantity), updated AS (UPDATE inventory SET reserved = inventory.reserved - fulfilled.quantity, stock_on_hand = inventory.stock_on_hand - fulfilled.quantity FROM fulfilled WHERE inventory.product_id = fulfilled.product_id) INSERT INTO inventory_movements (product_id, kind, on_hand_change, reserved_change, order_id) SELECT product_id, $2, -quantity, -quantity, $1 FROM fulfilled;",
+        order_id,
+        kind_name(MovementKind::Sale)
+    )
+    .execute(tx)
+    .await
+    .map_err(DbError::from)?;
+
+    Ok(())
+}
+
+/// Applies the stock effect of an order moving to `status`. Goods that come
+/// back after fulfilment are put back with `AdjustStock`.
+pub(crate) async fn apply_transition(
+    tx: &mut sqlx::PgConnection,
+    order_id: i32,
+    status: OrderStatus,
+) -> Result<(), tonic::Status> {
+    match status {
+        OrderStatus::Fulfilling => fulfil(tx, order_id).await,
+        OrderStatus::Cancelled | OrderStatus::Refunded => release(tx, order_id).await,
+        _ => Ok(()),
+    }
+}
+
+/// Stock of `product_ids`, or of every product when empty.
+pub(crate) async fn levels<'e, E: PgExecutor<'e>>(
+    executor: E,
+    product_ids: &[i32],
+) -> Result<Vec<proto::StockLevel>, tonic::Status> {
+    query_as!(
+        proto::StockLevel,
+        r#"SELECT products.product_id, COALESCE(inventory.stock_on_hand, 0) AS "stock_on_hand!", COALESCE(inventory.reserved, 0) AS "reserved!", COALESCE(inventory.stock_on_hand - inventory.reserved, 0) AS "available!"
+            FROM products
+            LEFT JOIN inventory ON inventory.product_id = products.product_id
+            WHERE cardinality($1::INT[]) = 0 OR products.product_id = ANY($1)
+            ORDER BY products.product_id;"#,
+        product_ids
+    )
+    .fetch_all(executor)
+    .await
+    .map_err(|e| DbError::from(e).into())
+}
+
+/// Whether an admin may record `kind` with a change of `delta`. Sales,
+/// reservations and releases only come from orders.
+fn check_adjustment(kind: MovementKind, delta: i32) -> Result<(), tonic::Status> {
+    let rule = match kind {
+        MovementKind::Receipt | MovementKind::Return if delta < 0 => "Must be positive",
+        MovementKind::Damage if delta > 0 => "Must be negative",
+        MovementKind::Receipt
+        | MovementKind::Return
+        | MovementKind::Damage
+        | MovementKind::Adjustment
+        | MovementKind::Transfer => return Ok(()),
+        MovementKind::Unspecified
+        | MovementKind::Sale
+        | MovementKind::Reservation
+        | MovementKind::Release => {
+            return Err(error::with_details(
+                Code::InvalidArgument,
+                "kind: Not a manual stock movement",
+                vec![error::bad_request(&[(
+                    "kind",
+                    "Not a manual stock movement",
+                )])],
+            ))
+        }
+    };
+
+    let description = format!("{} for a {} movement", rule, kind_name(kind));
+
+    Err(error::with_details(
+        Code::InvalidArgument,
+        format!("delta: {}", description),
+        vec![error::bad_request(&[("delta", &description)])],
+    ))
+}
+
+/// Adds `delta` (negative to remove) to a product's stock on hand and records
+/// why.
+pub(crate) async fn adjust(
+    db_pool: &sqlx::PgPool,
+    product_id: i32,
+    delta: i32,
+    kind: MovementKind,
+    admin_id: i32,
+    note: &str,
+) -> Result<proto::StockLevel, tonic::Status> {
+    check_adjustment(kind, delta)?;
+
+    let mut tx = db_pool.begin().await.map_err(DbError::from)?;
+
+    // Create the row first: an upsert would check its constraints against
+    // the row it proposes to insert, which a removal always breaks.
+    query!(
+        "INSERT INTO inventory (product_id) VALUES ($1) ON CONFLICT (product_id) DO NOTHING;",
+        product_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(DbError::from)?;
+
+    let level = query_as!(
+        proto::StockLevel,
+        r#"WITH level AS (
+                UPDATE inventory SET stock_on_hand = stock_on_hand + $2 WHERE product_id = $1
+                RETURNING product_id, stock_on_hand, reserved
+            ), movement AS (
+                INSERT INTO inventory_movements (product_id, kind, on_hand_change, reserved_change, admin_id, note)
+                SELECT product_id, $3, $2, 0, $4, $5 FROM level
+            )
+            SELECT product_id AS "product_id!", stock_on_hand AS "stock_on_hand!", reserved AS "reserved!", stock_on_hand - reserved AS "available!" FROM level;"#,
+        product_id,
+        delta,
+        kind_name(kind),
+        admin_id,
+        note
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(DbError::from)?;
+
+    tx.commit().await.map_err(DbError::from)?;
+
+    Ok(level)
+}
+
+/// An `inventory_movements` row.
+#[derive(sqlx::FromRow)]
+struct MovementRow {
+    movement_id: i32,
+    product_id: i32,
+    kind: String,
+    on_hand_change: i32,
+    reserved_change: i32,
+    order_id: Option<i32>,
+    admin_id: Option<i32>,
+    note: String,
+    created_at: OffsetDateTime,
+}
+
+const MOVEMENT_LISTING: Listing = Listing {
+    select: "*",
+    from: "inventory_movements",
+    id: "movement_id",
+    fields: &[
+        Field {
+            name: "movement_id",
+            column: "movement_id",
+            kind: Kind::Int,
+            sortable: true,
+        },
+        Field {
+            name: "product_id",
+            column: "product_id",
+            kind: Kind::Int,
+            sortable: false,
+        },
+        Field {
+            name: "kind",
+            column: "kind",
+            kind: Kind::Enum(filter_kind),
+            sortable: false,
+        },
+        Field {
+            name: "order_id",
+            column: "order_id",
+            kind: Kind::Int,
+            sortable: false,
+        },
+        Field {
+            name: "admin_id",
+            column: "admin_id",
+            kind: Kind::Int,
+            sortable: false,
+        },
+        Field {
+            name: "created_at",
+            column: "created_at",
+            kind: Kind::Timestamp,
+            sortable: true,
+        },
+    ],
+    default_order: "created_at",
+};
+
+/// A page of the movements of `request.product_id` (0 for every product)
+/// made in `[since, until)`, where either bound may be open.
+pub(crate) async fn movements<'e, E: PgExecutor<'e>>(
+    executor: E,
+    request: &proto::ListInventoryMovementsRequest,
+) -> Result<Page<proto::InventoryMovement>, tonic::Status> {
+    let mut scope = Vec::new();
+    if request.product_id != 0 {
+        scope.push(Condition::eq("product_id", Value::Int(request.product_id)));
+    }
+    if let Some(since) = request.since.as_ref().and_then(timestamp::from_proto) {
+        scope.push(Condition {
+            column: "created_at",
+            op: Op::Ge,
+            value: Value::Timestamp(since),
+        });
+    }
+    if let Some(until) = request.until.as_ref().and_then(timestamp::from_proto) {
+        scope.push(Condition {
+            column: "created_at",
+            op: Op::Lt,
+            value: Value::Timestamp(until),
+        });
+    }
+
+    let page: Page<MovementRow> = page::fetch(executor, &MOVEMENT_LISTING, request, scope).await?;
+
+    Ok(Page {
+        items: page
+            .items
+            .into_iter()
+            .map(|movement| proto::InventoryMovement {
+                movement_id: movement.movement_id,
+                product_id: movement.product_id,
+                kind: parse_kind(&movement.kind) as i32,
+                on_hand_change: movement.on_hand_change,
+                reserved_change: movement.reserved_change,
+                order_id: movement.order_id.unwrap_or_default(),
+                admin_id: movement.admin_id.unwrap_or_default(),
+                note: movement.note,
+                created_at: Some(timestamp::to_proto(movement.created_at)),
+            })
+            .collect(),
+        next_page_token: page.next_page_token,
+    })
+}
+
+/// Products whose stock levels don't match the sum of their movements.
+pub(crate) async fn discrepancies<'e, E: PgExecutor<'e>>(
+    executor: E,
+) -> Result<Vec<proto::InventoryDiscrepancy>, tonic::Status> {
+    query_as!(
+        proto::InventoryDiscrepancy,
+        r#"SELECT
+                products.product_id,
+                COALESCE(inventory.stock_on_hand, 0) AS "stock_on_hand!",
+                COALESCE(ledger.on_hand, 0)::INT AS "ledger_stock_on_hand!",
+                COALESCE(inventory.reserved, 0) AS "reserved!",
+                COALESCE(ledger.reserved, 0)::INT AS "ledger_reserved!"
+            FROM products
+            LEFT JOIN inventory ON inventory.product_id = products.product_id
+            LEFT JOIN (
+                SELECT product_id, sum(on_hand_change) AS on_hand, sum(reserved_change) AS reserved
+                FROM inventory_movements
+                GROUP BY product_id
+            ) AS ledger ON ledger.product_id = products.product_id
+            WHERE COALESCE(inventory.stock_on_hand, 0) <> COALESCE(ledger.on_hand, 0)
+                OR COALESCE(inventory.reserved, 0) <> COALESCE(ledger.reserved, 0)
+            ORDER BY products.product_id;"#
+    )
+    .fetch_all(executor)
+    .await
+    .map_err(|e| DbError::from(e).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+
+    #[test]
+    fn kind_names_round_trip() {
+        assert_eq!(kind_name(MovementKind::Reservation), "reservation");
+        assert_eq!(parse_kind("damage"), MovementKind::Damage);
+        assert_eq!(parse_kind("lost"), MovementKind::Unspecified);
+        assert_eq!(filter_kind("RECEIPT").as_deref(), Some("receipt"));
+        assert_eq!(filter_kind("unspecified"), None);
+    }
+
+    #[test]
+    fn manual_movements_must_move_stock_the_right_way() {
+        for (kind, delta) in [
+            (MovementKind::Receipt, 5),
+            (MovementKind::Return, 1),
+            (MovementKind::Damage, -1),
+            (MovementKind::Adjustment, -5),
+            (MovementKind::Adjustment, 5),
+            (MovementKind::Transfer, -2),
+        ] {
+            assert!(
+                check_adjustment(kind, delta).is_ok(),
+                "{:?} {}",
+                kind,
+                delta
+            );
+        }
+
+        let status = check_adjustment(MovementKind::Receipt, -1).unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(
+            status.message(),
+            "delta: Must be positive for a receipt movement"
+        );
+
+        let status = check_adjustment(MovementKind::Damage, 1).unwrap_err();
+        assert_eq!(
+            status.message(),
+            "delta: Must be negative for a damage movement"
+        );
+    }
+
+    #[test]
+    fn order_movements_cannot_be_recorded_by_hand() {
+        for kind in [
+            MovementKind::Unspecified,
+            MovementKind::Sale,
+            MovementKind::Reservation,
+            MovementKind::Release,
+        ] {
+            let status = check_adjustment(kind, 1).unwrap_err();
+            assert_eq!(status.message(), "kind: Not a manual stock movement");
+        }
+    }
+
+    #[sqlx::test]
+    async fn ledger_explains_every_change(db_pool: sqlx::PgPool) {
+        let product_id = fixtures::product(&db_pool, "Widget").await;
+        let admin_id = fixtures::admin(&db_pool, "admin").await;
+
+        adjust(
+            &db_pool,
+            product_id,
+            10,
+            MovementKind::Receipt,
+            admin_id,
+            "",
+        )
+        .await
+        .unwrap();
+        let level = adjust(&db_pool, product_id, -2, MovementKind::Damage, admin_id, "")
+            .await
+            .unwrap();
+        assert_eq!((level.stock_on_hand, level.available), (8, 8));
+
+        // Removing more than is on hand leaves both stock and ledger alone.
+        adjust(
+            &db_pool,
+            product_id,
+            -9,
+            MovementKind::Adjustment,
+            admin_id,
+            "",
+        )
+        .await
+        .unwrap_err();
+
+        let request = proto::ListInventoryMovementsRequest {
+            product_id,
+            ..Default::default()
+        };
+        let changes: Vec<(i32, i32)> = movements(&db_pool, &request)
+            .await
+            .unwrap()
+            .items
+            .iter()
+            .map(|movement| (movement.kind, movement.on_hand_change))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (MovementKind::Receipt as i32, 10),
+                (MovementKind::Damage as i32, -2)
+            ]
+        );
+
+        assert!(discrepancies(&db_pool).await.unwrap().is_empty());
+    }
+
+    #[sqlx::test]
+    async fn discrepancies_report_stock_changed_outside_the_ledger(db_pool: sqlx::PgPool) {
+        let product_id = fixtures::product(&db_pool, "Widget").await;
+        fixtures::product(&db_pool, "Gadget").await;
+        let admin_id = fixtures::admin(&db_pool, "admin").await;
+
+        adjust(
+            &db_pool,
+            product_id,
+            10,
+            MovementKind::Receipt,
+            admin_id,
+            "",
+        )
+        .await
+        .unwrap();
+        query!(
+            "UPDATE inventory SET stock_on_hand = 4, reserved = 1 WHERE product_id = $1;",
+            product_id
+        )
+        .execute(&db_pool)
+        .await
+        .unwrap();
+
+        // The untouched product's empty stock row matches its empty ledger.
+        let found = discrepancies(&db_pool).await.unwrap();
+
+        assert_eq!(
+            found,
+            [proto::InventoryDiscrepancy {
+                product_id,
+                stock_on_hand: 4,
+                ledger_stock_on_hand: 10,
+                reserved: 1,
+                ledger_reserved: 0,
+            }]
+        );
+    }
+}
@@ -0,0 +1,132 @@
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use std::{error::Error, fmt, path::PathBuf, sync::Arc};
+use tokio::io::AsyncWriteExt;
+
+pub(crate) type MailError = Box<dyn Error + Send + Sync>;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Email {
+    pub(crate) to: String,
+    pub(crate) subject: String,
+    pub(crate) body: String,
+}
+
+impl fmt::Display for Email {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "To: {}\nSubject: {}\n\n{}\n",
+            self.to, self.subject, self.body
+        )
+    }
+}
+
+#[tonic::async_trait]
+pub(crate) trait Mailer: fmt::Debug + Send + Sync {
+    async fn send(&self, email: Email) -> Result<(), MailError>;
+}
+
+/// Prints every message, for local development.
+#[derive(Debug)]
+pub(crate) struct StdoutMailer;
+
+#[tonic::async_trait]
+impl Mailer for StdoutMailer {
+    async fn send(&self, email: Email) -> Result<(), MailError> {
+        println!("\nEMAIL:\n{}", email);
+        Ok(())
+    }
+}
+
+/// Appends every message to a file, so tests can read the tokens back.
+#[derive(Debug)]
+pub(crate) struct FileMailer {
+    path: PathBuf,
+}
+
+#[tonic::async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: Email) -> Result<(), MailError> {
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+
+        file.write_all(format!("{}\n", email).as_bytes()).await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+#[tonic::async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: Email) -> Result<(), MailError> {
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(email.to.parse()?)
+            .subject(email.subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(email.body)?;
+
+        self.transport.send(message).await?;
+
+        Ok(())
+    }
+}
+
+fn required_var(name: &str) -> Result<String, Box<dyn Error>> {
+    std::env::var(name).map_err(|_| format!("{} must be set", name).into())
+}
+
+/// Builds the mailer selected by `MAILER` (`stdout`, `file` or `smtp`).
+///
+/// `file` writes to `MAILER_FILE`. `smtp` connects to `SMTP_HOST`/`SMTP_PORT`
+/// with `SMTP_TLS` set to `tls`, `starttls` or `none` (for a local fake SMTP
+/// server), optional `SMTP_USERNAME`/`SMTP_PASSWORD`, and sends as `MAIL_FROM`.
+pub(crate) fn from_env() -> Result<Arc<dyn Mailer>, Box<dyn Error>> {
+    let kind = std::env::var("MAILER").unwrap_or_else(|_| "stdout".into());
+
+    match kind.as_str() {
+        "stdout" => Ok(Arc::new(StdoutMailer)),
+        "file" => Ok(Arc::new(FileMailer {
+            path: required_var("MAILER_FILE")?.into(),
+        })),
+        "smtp" => {
+            let host = required_var("SMTP_HOST")?;
+
+            let mut builder = match std::env::var("SMTP_TLS").as_deref() {
+                Ok("none") => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&host),
+                Ok("starttls") => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)?,
+                _ => AsyncSmtpTransport::<Tokio1Executor>::relay(&host)?,
+            };
+
+            if let Ok(port) = std::env::var("SMTP_PORT") {
+                builder = builder.port(port.parse()?);
+            }
+
+            if let (Ok(username), Ok(password)) = (
+                std::env::var("SMTP_USERNAME"),
+                std::env::var("SMTP_PASSWORD"),
+            ) {
+                builder = builder.credentials(Credentials::new(username, password));
+            }
+
+            Ok(Arc::new(SmtpMailer {
+                transport: builder.build(),
+                from: required_var("MAIL_FROM")?.parse()?,
+            }))
+        }
+        _ => Err(format!("Unknown mailer {}", kind).into()),
+    }
+}
@@ -1,3 +1,6 @@
+#![allow(clippy::result_large_err)]
+
+#[allow(dead_code)]
 mod proto {
     tonic::include_proto!("rust_ecom");
 
@@ -5,8 +8,42 @@ mod proto {
         tonic::include_file_descriptor_set!("rust_ecom_descriptor");
 }
 
+#[allow(dead_code)]
+mod google {
+    pub(crate) mod rpc {
+        tonic::include_proto!("google.rpc");
+    }
+}
+
+mod account;
+mod auth;
+mod bootstrap;
+mod cart;
+mod error;
+#[cfg(test)]
+mod fixtures;
+mod idempotency;
+mod inventory;
+mod mailer;
+mod mask;
+mod money;
+mod order;
+mod page;
+mod panic;
+mod password;
+mod product;
+mod rbac;
+mod redact;
 mod server;
+mod session;
+mod telemetry;
+mod timestamp;
+mod token;
+mod totp;
+mod validate;
 
+use auth::AuthService;
+use idempotency::IdempotencyService;
 use server::*;
 use std::{error::Error, sync::Arc};
 
@@ -14,14 +51,15 @@ use proto::{
     admin_server::AdminServer, storefront_server::StorefrontServer, user_server::UserServer,
 };
 use tonic::transport::Server;
+use tower_http::catch_panic::CatchPanicLayer;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     dotenv::dotenv().ok();
 
-    let addr = std::env::var("SERVICE_ADDRESS")
-        .expect("SERVICE_ADDRESS must be set")
-        .parse()?;
+    telemetry::init()?;
+    panic::install_hook();
+
     let db_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
 
     let conn_pool = Arc::new(sqlx::PgPool::connect(&db_url).await?);
@@ -30,33 +68,49 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .run(conn_pool.as_ref())
         .await?;
 
-    let _ = sqlx::query!(
-        "INSERT INTO admins (admin_id, username, password, email, created_at) 
-            VALUES ($1, $2, $3, $4, $5)",
-        0,
-        "admin",
-        "admin",
-        "admin",
-        0.0
-    )
-    .execute(conn_pool.as_ref())
-    .await;
-
-    let storefront_service = StorefrontService::new(conn_pool.clone());
+    bootstrap::remove_seeded_admin(conn_pool.as_ref()).await?;
+
+    if let Some(command) = std::env::args().nth(1) {
+        return match command.as_str() {
+            "bootstrap-admin" => bootstrap::bootstrap_admin(conn_pool.as_ref()).await,
+            _ => Err(format!("Unknown command {}", command).into()),
+        };
+    }
+
+    let addr = std::env::var("SERVICE_ADDRESS")
+        .expect("SERVICE_ADDRESS must be set")
+        .parse()?;
+    once_cell::sync::Lazy::force(&auth::KEYS);
+
+    let mailer = mailer::from_env()?;
+
+    let storefront_service = StorefrontService::new(conn_pool.clone(), mailer.clone());
     let admin_service = AdminService::new(conn_pool.clone());
-    let user_service = UserService::new(conn_pool.clone());
+    let user_service = UserService::new(conn_pool.clone(), mailer);
 
     let reflection_server = tonic_reflection::server::Builder::configure()
         .register_encoded_file_descriptor_set(proto::FILE_DESCRIPTOR_SET)
         .build()?;
 
-    println!("Listening on {}\n", addr);
+    tracing::info!(%addr, "Listening");
 
     Server::builder()
+        .layer(telemetry::TraceLayer)
+        .layer(CatchPanicLayer::custom(panic::response))
+        .layer(tonic::service::interceptor(redact::sensitive_metadata))
         .add_service(reflection_server)
-        .add_service(StorefrontServer::new(storefront_service))
-        .add_service(AdminServer::new(admin_service))
-        .add_service(UserServer::new(user_service))
+        .add_service(IdempotencyService::new(
+            StorefrontServer::new(storefront_service),
+            conn_pool.clone(),
+        ))
+        .add_service(AuthService::admin(
+            IdempotencyService::new(AdminServer::new(admin_service), conn_pool.clone()),
+            conn_pool.clone(),
+        ))
+        .add_service(AuthService::user(
+            IdempotencyService::new(UserServer::new(user_service), conn_pool.clone()),
+            conn_pool.clone(),
+        ))
         .serve(addr)
         .await?;
 
@@ -0,0 +1,117 @@
+use crate::proto;
+
+/// An update request whose `update_mask` picks the fields it writes.
+pub(crate) trait Update {
+    /// The fields a mask may name.
+    const FIELDS: &'static [&'static str];
+
+    /// Whether the request writes `field`: it's in the mask, or the mask is
+    /// empty and the field is set to a non-default value.
+    fn writes(&self, field: &str) -> bool;
+}
+
+fn is_set<T: Default + PartialEq>(value: &T) -> bool {
+    *value != T::default()
+}
+
+macro_rules! masked {
+    ($($message:ident { $($field:ident)* })*) => {$(
+        impl Update for proto::$message {
+            const FIELDS: &'static [&'static str] = &[$(stringify!($field)),*];
+
+            fn writes(&self, field: &str) -> bool {
+                match &self.update_mask {
+                    Some(mask) if !mask.paths.is_empty() => {
+                        mask.paths.iter().any(|path| path == field)
+                    }
+                    _ => match field {
+                        $(stringify!($field) => is_set(&self.$field),)*
+                        _ => false,
+                    },
+                }
+            }
+        }
+    )*};
+}
+
+masked! {
+    UpdateProductRequest { name description price }
+    UpdateOrderRequest { user_id total }
+    UpdateAdminAccountRequest { username password email }
+    UpdateUserAccountRequest { username password email }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost_types::FieldMask;
+
+    fn mask(paths: &[&str]) -> Option<FieldMask> {
+        Some(FieldMask {
+            paths: paths.iter().map(|path| path.to_string()).collect(),
+        })
+    }
+
+    fn update(update_mask: Option<FieldMask>) -> proto::UpdateProductRequest {
+        proto::UpdateProductRequest {
+            product_id: 1,
+            name: "Widget".to_owned(),
+            update_mask,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn empty_mask_writes_set_fields() {
+        for request in [update(None), update(mask(&[]))] {
+            assert!(request.writes("name"));
+            assert!(!request.writes("description"));
+            assert!(!request.writes("price"));
+        }
+
+        let request = proto::UpdateProductRequest {
+            price: Some(proto::Money::default()),
+            ..update(None)
+        };
+        assert!(request.writes("price"));
+    }
+
+    #[test]
+    fn mask_writes_exactly_its_paths() {
+        let request = update(mask(&["description", "price"]));
+
+        assert!(!request.writes("name"));
+        assert!(request.writes("description"));
+        assert!(request.writes("price"));
+    }
+
+    #[test]
+    fn account_updates_write_passwords_only_when_asked() {
+        let request = proto::UpdateUserAccountRequest {
+            email: "user@example.com".to_owned(),
+            ..Default::default()
+        };
+        assert!(request.writes("email"));
+        assert!(!request.writes("password"));
+
+        let request = proto::UpdateAdminAccountRequest {
+            password: "correct horse".to_owned(),
+            update_mask: mask(&["password"]),
+            ..Default::default()
+        };
+        assert!(request.writes("password"));
+        assert!(!request.writes("username"));
+    }
+
+    #[test]
+    fn only_updatable_fields_are_written() {
+        assert_eq!(
+            proto::UpdateProductRequest::FIELDS,
+            ["name", "description", "price"]
+        );
+
+        assert!(!update(None).writes("product_id"));
+        assert!(!update(None).writes("update_mask"));
+        assert!(!update(mask(&["name"])).writes("product_id"));
+    }
+}
@@ -0,0 +1,152 @@
+use rust_decimal::{prelude::ToPrimitive, Decimal};
+
+use crate::proto;
+
+/// Amounts are stored as `NUMERIC(19, 4)`.
+const SCALE: u32 = 4;
+pub(crate) const MAX_UNITS: i64 = 999_999_999_999_999;
+const NANOS_PER_UNIT: i32 = 1_000_000_000;
+
+pub(crate) fn to_proto(amount: Decimal, currency_code: &str) -> proto::Money {
+    let units = amount.trunc();
+    let nanos = (amount - units) * Decimal::from(NANOS_PER_UNIT);
+
+    proto::Money {
+        currency_code: currency_code.to_owned(),
+        units: units.to_i64().unwrap_or_default(),
+        nanos: nanos.trunc().to_i32().unwrap_or_default(),
+    }
+}
+
+/// The amount of a [`check`]ed `Money`.
+pub(crate) fn amount(money: &proto::Money) -> Decimal {
+    Decimal::from(money.units) + Decimal::new(money.nanos.into(), 9)
+}
+
+/// Whether a computed `amount`, such as an order total, can be stored.
+pub(crate) fn fits(amount: Decimal) -> bool {
+    amount.trunc().abs() <= Decimal::from(MAX_UNITS)
+}
+
+/// Why `money` can't be stored, if it can't.
+pub(crate) fn check(money: &proto::Money) -> Result<(), String> {
+    let currency_code = &money.currency_code;
+    if currency_code.len() != 3 || !currency_code.bytes().all(|b| b.is_ascii_uppercase()) {
+        return Err("Must have a three-letter ISO 4217 currency code".to_owned());
+    }
+    if money.units.abs() > MAX_UNITS {
+        return Err(format!("Must be at most {} units either way", MAX_UNITS));
+    }
+    if money.nanos.abs() >= NANOS_PER_UNIT
+        || (money.units > 0 && money.nanos < 0)
+        || (money.units < 0 && money.nanos > 0)
+    {
+        return Err("Must have nanos within a unit and of the same sign as units".to_owned());
+    }
+    if money.nanos % 10_i32.pow(9 - SCALE) != 0 {
+        return Err(format!("Must have at most {} decimal places", SCALE));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usd(units: i64, nanos: i32) -> proto::Money {
+        proto::Money {
+            currency_code: "USD".to_owned(),
+            units,
+            nanos,
+        }
+    }
+
+    #[test]
+    fn check_accepts_storable_amounts() {
+        for money in [
+            usd(0, 0),
+            usd(12, 340_000_000),
+            usd(-12, -340_000_000),
+            usd(0, -100_000),
+            usd(MAX_UNITS, 999_900_000),
+            usd(-MAX_UNITS, -999_900_000),
+        ] {
+            assert_eq!(check(&money), Ok(()), "{:?}", money);
+        }
+    }
+
+    #[test]
+    fn check_rejects_what_numeric_19_4_cannot_hold() {
+        for (money, error) in [
+            (
+                proto::Money {
+                    currency_code: "usd".to_owned(),
+                    ..usd(1, 0)
+                },
+                "Must have a three-letter ISO 4217 currency code",
+            ),
+            (
+                proto::Money {
+                    currency_code: "US".to_owned(),
+                    ..usd(1, 0)
+                },
+                "Must have a three-letter ISO 4217 currency code",
+            ),
+            (
+                usd(MAX_UNITS + 1, 0),
+                "Must be at most 999999999999999 units either way",
+            ),
+            (
+                usd(-MAX_UNITS - 1, 0),
+                "Must be at most 999999999999999 units either way",
+            ),
+            (
+                usd(0, NANOS_PER_UNIT),
+                "Must have nanos within a unit and of the same sign as units",
+            ),
+            (
+                usd(1, -1_000_000),
+                "Must have nanos within a unit and of the same sign as units",
+            ),
+            (
+                usd(-1, 1_000_000),
+                "Must have nanos within a unit and of the same sign as units",
+            ),
+            (usd(1, 50_000), "Must have at most 4 decimal places"),
+        ] {
+            assert_eq!(check(&money).unwrap_err(), error, "{:?}", money);
+        }
+    }
+
+    #[test]
+    fn amounts_round_trip_through_proto() {
+        for (money, decimal) in [
+            (usd(0, 0), Decimal::ZERO),
+            (usd(12, 340_000_000), Decimal::new(1234, 2)),
+            (usd(-12, -340_000_000), Decimal::new(-1234, 2)),
+            (usd(0, -100_000), Decimal::new(-1, 4)),
+            (
+                usd(MAX_UNITS, 999_900_000),
+                Decimal::from_i128_with_scale(9_999_999_999_999_999_999, 4),
+            ),
+        ] {
+            assert_eq!(amount(&money), decimal);
+            assert_eq!(to_proto(decimal, "USD"), money);
+        }
+    }
+
+    #[test]
+    fn fits_matches_the_stored_range() {
+        assert!(fits(Decimal::from_i128_with_scale(
+            9_999_999_999_999_999_999,
+            4
+        )));
+        assert!(fits(Decimal::from_i128_with_scale(
+            -9_999_999_999_999_999_999,
+            4
+        )));
+        assert!(!fits(Decimal::from(MAX_UNITS + 1)));
+        assert!(!fits(Decimal::from(-MAX_UNITS - 1)));
+    }
+}
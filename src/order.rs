@@ -0,0 +1,433 @@
+use rust_decimal::Decimal;
+use sqlx::types::time::OffsetDateTime;
+use sqlx::{query, query_as, query_scalar, PgExecutor};
+use std::collections::HashMap;
+use tonic::Code;
+
+use crate::auth::Principal;
+use crate::error::{self, DbError};
+use crate::page::{Field, Kind, Listing};
+use crate::proto::{self, OrderStatus};
+use crate::{inventory, money, timestamp};
+
+/// An `orders` row. Its line items live in `order_items`.
+#[derive(Debug, sqlx::FromRow)]
+pub(crate) struct OrderRow {
+    pub(crate) order_id: i32,
+    pub(crate) user_id: i32,
+    pub(crate) total: Decimal,
+    pub(crate) status: String,
+    pub(crate) created_at: OffsetDateTime,
+    pub(crate) currency_code: String,
+    pub(crate) updated_at: OffsetDateTime,
+    pub(crate) version: i32,
+}
+
+pub(crate) const LISTING: Listing = Listing {
+    select: "*",
+    from: "orders",
+    id: "order_id",
+    fields: &[
+        Field {
+            name: "order_id",
+            column: "order_id",
+            kind: Kind::Int,
+            sortable: true,
+        },
+        Field {
+            name: "user_id",
+            column: "user_id",
+            kind: Kind::Int,
+            sortable: true,
+        },
+        Field {
+            name: "status",
+            column: "status",
+            kind: Kind::Enum(filter_status),
+            sortable: false,
+        },
+        Field {
+            name: "total",
+            column: "total",
+            kind: Kind::Decimal,
+            sortable: true,
+        },
+        Field {
+            name: "currency_code",
+            column: "currency_code",
+            kind: Kind::Text,
+            sortable: false,
+        },
+        Field {
+            name: "created_at",
+            column: "created_at",
+            kind: Kind::Timestamp,
+            sortable: true,
+        },
+        Field {
+            name: "updated_at",
+            column: "updated_at",
+            kind: Kind::Timestamp,
+            sortable: true,
+        },
+    ],
+    default_order: "order_id",
+};
+
+/// Loads the line items of `rows` and assembles the full orders.
+pub(crate) async fn with_items<'e, E: PgExecutor<'e>>(
+    executor: E,
+    rows: Vec<OrderRow>,
+) -> Result<Vec<proto::Order>, tonic::Status> {
+    let order_ids: Vec<i32> = rows.iter().map(|row| row.order_id).collect();
+
+    let currency_codes: HashMap<i32, &str> = rows
+        .iter()
+        .map(|row| (row.order_id, row.currency_code.as_str()))
+        .collect();
+    let mut items: HashMap<i32, Vec<proto::LineItem>> = HashMap::new();
+
+    query!(
+        "SELECT order_id, product_id, quantity, unit_price, name_snapshot FROM order_items WHERE order_id = ANY($1) ORDER BY order_id, product_id;",
+        &order_ids
+    )
+    .fetch_all(executor)
+    .await
+    .map_err(DbError::from)?
+    .into_iter()
+    .for_each(|item| {
+        items.entry(item.order_id).or_default().push(proto::LineItem {
+            product_id: item.product_id,
+            quantity: item.quantity,
+            unit_price: Some(money::to_proto(
+                item.unit_price,
+                currency_codes[&item.order_id],
+            )),
+            name: item.name_snapshot,
+        });
+    });
+
+    Ok(rows
+        .into_iter()
+        .map(|row| proto::Order {
+            items: items.remove(&row.order_id).unwrap_or_default(),
+            total: Some(money::to_proto(row.total, &row.currency_code)),
+            order_id: row.order_id,
+            user_id: row.user_id,
+            status: parse_status(&row.status) as i32,
+            created_at: Some(timestamp::to_proto(row.created_at)),
+            updated_at: Some(timestamp::to_proto(row.updated_at)),
+            version: row.version,
+        })
+        .collect())
+}
+
+/// The `orders.status` value for `status`, e.g. `pending`.
+pub(crate) fn status_name(status: OrderStatus) -> String {
+    status
+        .as_str_name()
+        .trim_start_matches("ORDER_STATUS_")
+        .to_lowercase()
+}
+
+/// The `orders.status` value for a status named in a filter, e.g. `shipped`.
+fn filter_status(name: &str) -> Option<String> {
+    match parse_status(name) {
+        OrderStatus::Unspecified => None,
+        status => Some(status_name(status)),
+    }
+}
+
+fn parse_status(name: &str) -> OrderStatus {
+    OrderStatus::from_str_name(&format!("ORDER_STATUS_{}", name.to_uppercase())).unwrap_or_default()
+}
+
+/// Statuses an order may move to from `status`.
+fn next_statuses(status: OrderStatus) -> &'static [OrderStatus] {
+    match status {
+        OrderStatus::Pending => &[OrderStatus::Paid, OrderStatus::Cancelled],
+        OrderStatus::Paid => &[OrderStatus::Fulfilling, OrderStatus::Refunded],
+        OrderStatus::Fulfilling => &[OrderStatus::Shipped, OrderStatus::Refunded],
+        OrderStatus::Shipped => &[OrderStatus::Delivered],
+        OrderStatus::Delivered => &[OrderStatus::Refunded],
+        OrderStatus::Unspecified | OrderStatus::Cancelled | OrderStatus::Refunded => &[],
+    }
+}
+
+/// Adds a change to the order's status history. `from` is `None` when the
+/// order is created.
+pub(crate) async fn record_status<'e, E: PgExecutor<'e>>(
+    executor: E,
+    order_id: i32,
+    from: Option<OrderStatus>,
+    to: OrderStatus,
+    changed_by: Principal,
+    note: &str,
+) -> Result<(), tonic::Status> {
+    let (admin_id, user_id) = match changed_by {
+        Principal::Admin(admin_id) => (Some(admin_id), None),
+        Principal::User(user_id) => (None, Some(user_id)),
+    };
+
+    query!(
+        "INSERT INTO order_status_history (order_id, from_status, to_status, admin_id, user_id, note) VALUES ($1, $2, $3, $4, $5, $6);",
+        order_id,
+        from.map(status_name),
+        status_name(to),
+        admin_id,
+        user_id,
+        note
+    )
+    .execute(executor)
+    .await
+    .map_err(DbError::from)?;
+
+    Ok(())
+}
+
+/// Moves an order to `to` if its current status allows it, and records the
+/// change.
+pub(crate) async fn transition(
+    tx: &mut sqlx::PgConnection,
+    order_id: i32,
+    to: OrderStatus,
+    changed_by: Principal,
+    note: &str,
+) -> Result<OrderRow, tonic::Status> {
+    let from = query_scalar!(
+        "SELECT status FROM orders WHERE order_id = $1 FOR UPDATE;",
+        order_id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(DbError::from)?
+    .ok_or_else(|| tonic::Status::not_found("Order not found"))?;
+    let from = parse_status(&from);
+
+    if !next_statuses(from).contains(&to) {
+        let (from, to) = (status_name(from), status_name(to));
+        let message = format!("Cannot move a {} order to {}", from, to);
+
+        return Err(error::with_details(
+            Code::FailedPrecondition,
+            message.clone(),
+            vec![
+                error::error_info("INVALID_ORDER_TRANSITION", &[("from", &from), ("to", &to)]),
+                error::precondition_failure(&[(
+                    "ORDER_STATUS",
+                    &format!("orders/{}", order_id),
+                    &message,
+                )]),
+            ],
+        ));
+    }
+
+    let row = query_as!(
+        OrderRow,
+        "UPDATE orders SET status = $1 WHERE order_id = $2 RETURNING *;",
+        status_name(to),
+        order_id
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(DbError::from)?;
+
+    record_status(&mut *tx, order_id, Some(from), to, changed_by, note).await?;
+    inventory::apply_transition(tx, order_id, to).await?;
+
+    Ok(row)
+}
+
+pub(crate) async fn status_history<'e, E: PgExecutor<'e>>(
+    executor: E,
+    order_id: i32,
+) -> Result<Vec<proto::OrderStatusChange>, tonic::Status> {
+    let changes = query!(
+        "SELECT from_status, to_status, admin_id, user_id, note, changed_at FROM order_status_history WHERE order_id = $1 ORDER BY changed_at, history_id;",
+        order_id
+    )
+    .fetch_all(executor)
+    .await
+    .map_err(DbError::from)?
+    .into_iter()
+    .map(|change| proto::OrderStatusChange {
+        from_status: change.from_status.as_deref().map(parse_status).unwrap_or_default() as i32,
+        to_status: parse_status(&change.to_status) as i32,
+        admin_id: change.admin_id.unwrap_or_default(),
+        user_id: change.user_id.unwrap_or_default(),
+        note: change.note,
+        changed_at: Some(timestamp::to_proto(change.changed_at)),
+    })
+    .collect();
+
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::proto::MovementKind;
+
+    const STATUSES: [OrderStatus; 7] = [
+        OrderStatus::Pending,
+        OrderStatus::Paid,
+        OrderStatus::Fulfilling,
+        OrderStatus::Shipped,
+        OrderStatus::Delivered,
+        OrderStatus::Cancelled,
+        OrderStatus::Refunded,
+    ];
+
+    #[test]
+    fn status_names_round_trip() {
+        for status in STATUSES {
+            assert_eq!(parse_status(&status_name(status)), status);
+        }
+
+        assert_eq!(status_name(OrderStatus::Fulfilling), "fulfilling");
+        assert_eq!(filter_status("SHIPPED").as_deref(), Some("shipped"));
+        assert_eq!(filter_status("unspecified"), None);
+        assert_eq!(filter_status("lost"), None);
+    }
+
+    #[test]
+    fn orders_move_forward_through_their_lifecycle() {
+        use OrderStatus::*;
+
+        assert_eq!(next_statuses(Pending), [Paid, Cancelled]);
+        assert_eq!(next_statuses(Paid), [Fulfilling, Refunded]);
+        assert_eq!(next_statuses(Fulfilling), [Shipped, Refunded]);
+        assert_eq!(next_statuses(Shipped), [Delivered]);
+        assert_eq!(next_statuses(Delivered), [Refunded]);
+
+        for status in STATUSES {
+            assert!(!next_statuses(status).contains(&status), "{:?}", status);
+            assert!(!next_statuses(status).contains(&Pending), "{:?}", status);
+        }
+    }
+
+    #[test]
+    fn cancelled_and_refunded_orders_are_final() {
+        assert!(next_statuses(OrderStatus::Cancelled).is_empty());
+        assert!(next_statuses(OrderStatus::Refunded).is_empty());
+        assert!(next_statuses(OrderStatus::Unspecified).is_empty());
+    }
+
+    /// An admin, a product with 10 in stock and a pending order for 3 of it.
+    async fn pending_order(db_pool: &sqlx::PgPool) -> (i32, i32, i32) {
+        let admin_id = fixtures::admin(db_pool, "admin").await;
+        let user_id = fixtures::user(db_pool, "user").await;
+        let product_id = fixtures::product(db_pool, "Widget").await;
+        inventory::adjust(db_pool, product_id, 10, MovementKind::Receipt, admin_id, "")
+            .await
+            .unwrap();
+
+        let mut tx = db_pool.begin().await.unwrap();
+        let order_id = query_scalar!(
+            "INSERT INTO orders (user_id, total, currency_code, status) VALUES ($1, 6, 'USD', 'pending') RETURNING order_id;",
+            user_id
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .unwrap();
+        let items = [proto::LineItem {
+            product_id,
+            quantity: 3,
+            ..Default::default()
+        }];
+        inventory::reserve(&mut tx, order_id, &items).await.unwrap();
+        tx.commit().await.unwrap();
+
+        (admin_id, product_id, order_id)
+    }
+
+    async fn stock(db_pool: &sqlx::PgPool, product_id: i32) -> proto::StockLevel {
+        inventory::levels(db_pool, &[product_id])
+            .await
+            .unwrap()
+            .remove(0)
+    }
+
+    #[sqlx::test]
+    async fn transition_applies_stock_and_records_history(db_pool: sqlx::PgPool) {
+        let (admin_id, product_id, order_id) = pending_order(&db_pool).await;
+        let admin = Principal::Admin(admin_id);
+
+        for to in [OrderStatus::Paid, OrderStatus::Fulfilling] {
+            let mut tx = db_pool.begin().await.unwrap();
+            let row = transition(&mut tx, order_id, to, admin, "").await.unwrap();
+            tx.commit().await.unwrap();
+            assert_eq!(row.status, status_name(to));
+        }
+
+        let level = stock(&db_pool, product_id).await;
+        assert_eq!((level.stock_on_hand, level.reserved), (7, 0));
+
+        let history = status_history(&db_pool, order_id).await.unwrap();
+        let moves: Vec<(i32, i32)> = history
+            .iter()
+            .map(|change| (change.from_status, change.to_status))
+            .collect();
+        assert_eq!(
+            moves,
+            [
+                (OrderStatus::Pending as i32, OrderStatus::Paid as i32),
+                (OrderStatus::Paid as i32, OrderStatus::Fulfilling as i32),
+            ]
+        );
+        assert!(history.iter().all(|change| change.admin_id == admin_id));
+    }
+
+    #[sqlx::test]
+    async fn cancelling_releases_the_reservation(db_pool: sqlx::PgPool) {
+        let (admin_id, product_id, order_id) = pending_order(&db_pool).await;
+
+        let mut tx = db_pool.begin().await.unwrap();
+        transition(
+            &mut tx,
+            order_id,
+            OrderStatus::Cancelled,
+            Principal::Admin(admin_id),
+            "",
+        )
+        .await
+        .unwrap();
+        tx.commit().await.unwrap();
+
+        let level = stock(&db_pool, product_id).await;
+        assert_eq!((level.stock_on_hand, level.reserved), (10, 0));
+    }
+
+    #[sqlx::test]
+    async fn transition_rejects_moves_the_status_does_not_allow(db_pool: sqlx::PgPool) {
+        let (admin_id, product_id, order_id) = pending_order(&db_pool).await;
+
+        let mut tx = db_pool.begin().await.unwrap();
+        let status = transition(
+            &mut tx,
+            order_id,
+            OrderStatus::Shipped,
+            Principal::Admin(admin_id),
+            "",
+        )
+        .await
+        .unwrap_err();
+        drop(tx);
+
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        assert_eq!(status.message(), "Cannot move a pending order to shipped");
+        assert!(status_history(&db_pool, order_id).await.unwrap().is_empty());
+        assert_eq!(stock(&db_pool, product_id).await.reserved, 3);
+    }
+
+    #[sqlx::test]
+    async fn transition_fails_for_a_missing_order(db_pool: sqlx::PgPool) {
+        let mut tx = db_pool.begin().await.unwrap();
+        let status = transition(&mut tx, 1, OrderStatus::Paid, Principal::Admin(1), "")
+            .await
+            .unwrap_err();
+
+        assert_eq!(status.code(), Code::NotFound);
+    }
+}
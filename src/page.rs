@@ -0,0 +1,696 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::{PgRow, Postgres};
+use sqlx::types::time::OffsetDateTime;
+use sqlx::{FromRow, PgExecutor, QueryBuilder, Row};
+use std::str::FromStr;
+use tonic::Code;
+
+use crate::error::{self, DbError};
+use crate::{proto, timestamp, token};
+
+const DEFAULT_PAGE_SIZE: i32 = 100;
+pub(crate) const MAX_PAGE_SIZE: i32 = 1000;
+
+/// Paging, filtering and sorting fields of a list request.
+pub(crate) trait Request {
+    fn page_size(&self) -> i32;
+    fn page_token(&self) -> &str;
+    fn filter(&self) -> &str;
+    fn order_by(&self) -> &str;
+}
+
+macro_rules! paged {
+    ($($message:ident)*) => {$(
+        impl Request for proto::$message {
+            fn page_size(&self) -> i32 {
+                self.page_size
+            }
+
+            fn page_token(&self) -> &str {
+                &self.page_token
+            }
+
+            fn filter(&self) -> &str {
+                &self.filter
+            }
+
+            fn order_by(&self) -> &str {
+                &self.order_by
+            }
+        }
+    )*};
+}
+
+paged! {
+    GetProductsRequest
+    GetUserProductsRequest
+    GetOrdersRequest
+    GetUserOrdersRequest
+    GetAdminAccountsRequest
+    GetUserAccountsRequest
+    ListInventoryMovementsRequest
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum Kind {
+    Int,
+    Text,
+    Bool,
+    Decimal,
+    Timestamp,
+    /// A text column holding one of a proto enum's values, which parses the
+    /// name given in a filter into the stored value.
+    Enum(fn(&str) -> Option<String>),
+}
+
+/// A request field that can be filtered on, and sorted by if it's never null.
+pub(crate) struct Field {
+    pub(crate) name: &'static str,
+    pub(crate) column: &'static str,
+    pub(crate) kind: Kind,
+    pub(crate) sortable: bool,
+}
+
+/// How to list one kind of row.
+pub(crate) struct Listing {
+    /// The selected columns, e.g. `products.*`.
+    pub(crate) select: &'static str,
+    pub(crate) from: &'static str,
+    /// Unique column that breaks ties between equal sort keys.
+    pub(crate) id: &'static str,
+    pub(crate) fields: &'static [Field],
+    /// Sort field when `order_by` is empty.
+    pub(crate) default_order: &'static str,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Value {
+    Int(i32),
+    Text(String),
+    Bool(bool),
+    Decimal(Decimal),
+    Timestamp(OffsetDateTime),
+}
+
+impl Value {
+    fn parse(kind: Kind, literal: &str) -> Option<Self> {
+        match kind {
+            Kind::Int => literal.parse().ok().map(Value::Int),
+            Kind::Text => Some(Value::Text(literal.to_owned())),
+            Kind::Bool => literal.parse().ok().map(Value::Bool),
+            Kind::Decimal => Decimal::from_str(literal).ok().map(Value::Decimal),
+            Kind::Timestamp => prost_types::Timestamp::from_str(literal)
+                .ok()
+                .as_ref()
+                .and_then(timestamp::from_proto)
+                .map(Value::Timestamp),
+            Kind::Enum(parse) => parse(literal).map(Value::Text),
+        }
+    }
+
+    /// Reads column `name` of `row` as a literal [`Value::parse`] accepts.
+    fn read(kind: Kind, row: &PgRow, name: &str) -> Result<String, sqlx::Error> {
+        Ok(match kind {
+            Kind::Int => row.try_get::<i32, _>(name)?.to_string(),
+            Kind::Text | Kind::Enum(_) => row.try_get(name)?,
+            Kind::Bool => row.try_get::<bool, _>(name)?.to_string(),
+            Kind::Decimal => row.try_get::<Decimal, _>(name)?.to_string(),
+            Kind::Timestamp => {
+                timestamp::to_proto(row.try_get::<OffsetDateTime, _>(name)?).to_string()
+            }
+        })
+    }
+
+    fn push_bind(self, query: &mut QueryBuilder<'_, Postgres>) {
+        match self {
+            Value::Int(value) => query.push_bind(value),
+            Value::Text(value) => query.push_bind(value),
+            Value::Bool(value) => query.push_bind(value),
+            Value::Decimal(value) => query.push_bind(value),
+            Value::Timestamp(value) => query.push_bind(value),
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Op {
+    fn parse(op: &str) -> Option<Self> {
+        match op {
+            "=" => Some(Op::Eq),
+            "!=" => Some(Op::Ne),
+            "<" => Some(Op::Lt),
+            "<=" => Some(Op::Le),
+            ">" => Some(Op::Gt),
+            ">=" => Some(Op::Ge),
+            _ => None,
+        }
+    }
+
+    fn as_sql(&self) -> &'static str {
+        match self {
+            Op::Eq => "=",
+            Op::Ne => "<>",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Condition {
+    pub(crate) column: &'static str,
+    pub(crate) op: Op,
+    pub(crate) value: Value,
+}
+
+impl Condition {
+    pub(crate) fn eq(column: &'static str, value: Value) -> Self {
+        Self {
+            column,
+            op: Op::Eq,
+            value,
+        }
+    }
+}
+
+/// Where the previous page ended, for the same scope, filter and order.
+#[derive(Serialize, Deserialize)]
+struct Cursor {
+    key: String,
+    id: i32,
+    query: String,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    /// The cursor in `page_token`, if it was issued for `query`.
+    fn decode(page_token: &str, query: &str) -> Option<Self> {
+        URL_SAFE_NO_PAD
+            .decode(page_token)
+            .ok()
+            .and_then(|json| serde_json::from_slice::<Cursor>(&json).ok())
+            .filter(|cursor| cursor.query == query)
+    }
+}
+
+/// Identifies the query a page token belongs to, including the scope the
+/// caller narrowed it to (e.g. one user's orders).
+fn query_digest(scope: &[Condition], request: &impl Request) -> String {
+    token::digest(&format!(
+        "{:?}\n{}\n{}",
+        scope,
+        request.filter(),
+        request.order_by()
+    ))
+}
+
+pub(crate) struct Page<T> {
+    pub(crate) items: Vec<T>,
+    /// Empty on the last page.
+    pub(crate) next_page_token: String,
+}
+
+fn invalid(field: &str, description: &str) -> tonic::Status {
+    error::with_details(
+        Code::InvalidArgument,
+        format!("{}: {}", field, description),
+        vec![error::bad_request(&[(field, description)])],
+    )
+}
+
+fn field<'a>(listing: &'a Listing, name: &str) -> Option<&'a Field> {
+    listing.fields.iter().find(|field| field.name == name)
+}
+
+/// Splits a filter into words, operators and quoted strings, which may
+/// contain `\"` and `\\` escapes. Quoted strings keep their quote in front.
+fn tokenize(filter: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = filter.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '"' => {
+                let mut token = String::from('"');
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => token.extend(chars.next()),
+                        Some(c) => token.push(c),
+                        None => return Err("Unterminated string".to_owned()),
+                    }
+                }
+                tokens.push(token);
+            }
+            '=' | '!' | '<' | '>' => {
+                let mut token = String::from(c);
+                if chars.next_if_eq(&'=').is_some() {
+                    token.push('=');
+                }
+                tokens.push(token);
+            }
+            _ => {
+                let mut token = String::from(c);
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace() && !"=!<>\"".contains(*c))
+                {
+                    token.push(c);
+                }
+                tokens.push(token);
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Parses `field op value [AND field op value ...]`, e.g.
+/// `price >= 10 AND created_at < "2024-01-01T00:00:00Z"`.
+fn parse_filter(listing: &Listing, filter: &str) -> Result<Vec<Condition>, String> {
+    let tokens = tokenize(filter)?;
+    let mut conditions = Vec::new();
+
+    if tokens.is_empty() {
+        return Ok(conditions);
+    }
+
+    for term in tokens.split(|token| token.eq_ignore_ascii_case("AND")) {
+        let [name, op, literal] = term else {
+            return Err(format!(
+                "Expected `field operator value` between ANDs, got `{}`",
+                term.join(" ")
+            ));
+        };
+
+        let field = field(listing, name).ok_or_else(|| format!("Unknown field `{}`", name))?;
+        let op = Op::parse(op).ok_or_else(|| format!("Unknown operator `{}`", op))?;
+        let literal = literal.strip_prefix('"').unwrap_or(literal);
+        let value = Value::parse(field.kind, literal)
+            .ok_or_else(|| format!("Invalid value `{}` for `{}`", literal, name))?;
+
+        conditions.push(Condition {
+            column: field.column,
+            op,
+            value,
+        });
+    }
+
+    Ok(conditions)
+}
+
+/// Parses `field [asc|desc]` into the sort field and whether it descends.
+fn parse_order<'a>(listing: &'a Listing, order_by: &str) -> Result<(&'a Field, bool), String> {
+    let words: Vec<&str> = order_by.split_whitespace().collect();
+    let (name, descending) = match words[..] {
+        [] => (listing.default_order, false),
+        [name] => (name, false),
+        [name, direction] if direction.eq_ignore_ascii_case("asc") => (name, false),
+        [name, direction] if direction.eq_ignore_ascii_case("desc") => (name, true),
+        _ => return Err("Must be a field name, optionally followed by asc or desc".to_owned()),
+    };
+
+    match field(listing, name) {
+        Some(field) if field.sortable => Ok((field, descending)),
+        _ => Err(format!("Can't sort by `{}`", name)),
+    }
+}
+
+/// Fetches one page of `listing`, narrowed by `scope` and the request's
+/// filter, in the requested order.
+pub(crate) async fn fetch<'e, T, E>(
+    executor: E,
+    listing: &Listing,
+    request: &impl Request,
+    scope: Vec<Condition>,
+) -> Result<Page<T>, tonic::Status>
+where
+    T: for<'r> FromRow<'r, PgRow> + Send + Unpin,
+    E: PgExecutor<'e>,
+{
+    let filter = parse_filter(listing, request.filter()).map_err(|e| invalid("filter", &e))?;
+    let (order, descending) =
+        parse_order(listing, request.order_by()).map_err(|e| invalid("order_by", &e))?;
+    let page_size = match request.page_size() {
+        0 => DEFAULT_PAGE_SIZE,
+        page_size => page_size.min(MAX_PAGE_SIZE),
+    };
+
+    // A token only makes sense for the query that produced it.
+    let query_digest = query_digest(&scope, request);
+    let after = match request.page_token() {
+        "" => None,
+        page_token => {
+            let cursor = Cursor::decode(page_token, &query_digest).ok_or_else(|| {
+                invalid(
+                    "page_token",
+                    "Must be a next_page_token for a request with the same fields",
+                )
+            })?;
+            let key = Value::parse(order.kind, &cursor.key)
+                .ok_or_else(|| invalid("page_token", "Invalid page token"))?;
+
+            Some((key, cursor.id))
+        }
+    };
+
+    let mut query = QueryBuilder::new(format!(
+        "SELECT {}, {} AS page_key, {} AS page_id FROM {} WHERE TRUE",
+        listing.select, order.column, listing.id, listing.from
+    ));
+
+    for condition in scope.into_iter().chain(filter) {
+        query.push(format!(
+            " AND {} {} ",
+            condition.column,
+            condition.op.as_sql()
+        ));
+        condition.value.push_bind(&mut query);
+    }
+
+    if let Some((key, id)) = after {
+        query.push(format!(
+            " AND ({}, {}) {} (",
+            order.column,
+            listing.id,
+            if descending { "<" } else { ">" }
+        ));
+        key.push_bind(&mut query);
+        query.push(", ");
+        query.push_bind(id);
+        query.push(")");
+    }
+
+    let direction = if descending { "DESC" } else { "ASC" };
+    query.push(format!(
+        " ORDER BY {} {}, {} {} LIMIT ",
+        order.column, direction, listing.id, direction
+    ));
+    query.push_bind(i64::from(page_size) + 1);
+
+    let mut rows = query
+        .build()
+        .fetch_all(executor)
+        .await
+        .map_err(DbError::from)?;
+
+    let next_page_token = match rows.len() > page_size as usize {
+        true => {
+            rows.truncate(page_size as usize);
+            let last = &rows[rows.len() - 1];
+            let cursor = Cursor {
+                key: Value::read(order.kind, last, "page_key").map_err(DbError::from)?,
+                id: last.try_get("page_id").map_err(DbError::from)?,
+                query: query_digest,
+            };
+
+            cursor.encode()
+        }
+        false => String::new(),
+    };
+
+    let items = rows
+        .iter()
+        .map(T::from_row)
+        .collect::<Result<_, _>>()
+        .map_err(DbError::from)?;
+
+    Ok(Page {
+        items,
+        next_page_token,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(name: &str) -> Option<String> {
+        matches!(name, "open" | "closed").then(|| name.to_owned())
+    }
+
+    const LISTING: Listing = Listing {
+        select: "*",
+        from: "things",
+        id: "thing_id",
+        fields: &[
+            Field {
+                name: "thing_id",
+                column: "thing_id",
+                kind: Kind::Int,
+                sortable: true,
+            },
+            Field {
+                name: "name",
+                column: "things.name",
+                kind: Kind::Text,
+                sortable: true,
+            },
+            Field {
+                name: "active",
+                column: "active",
+                kind: Kind::Bool,
+                sortable: false,
+            },
+            Field {
+                name: "price",
+                column: "price",
+                kind: Kind::Decimal,
+                sortable: true,
+            },
+            Field {
+                name: "created_at",
+                column: "created_at",
+                kind: Kind::Timestamp,
+                sortable: true,
+            },
+            Field {
+                name: "status",
+                column: "status",
+                kind: Kind::Enum(status),
+                sortable: false,
+            },
+        ],
+        default_order: "thing_id",
+    };
+
+    fn request(filter: &str, order_by: &str) -> proto::GetProductsRequest {
+        proto::GetProductsRequest {
+            filter: filter.to_owned(),
+            order_by: order_by.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn tokenize_splits_words_operators_and_strings() {
+        assert_eq!(
+            tokenize(r#"price>=10 AND name = "a b""#).unwrap(),
+            ["price", ">=", "10", "AND", "name", "=", "\"a b"]
+        );
+        assert_eq!(
+            tokenize("a!=b c<d e>f").unwrap(),
+            ["a", "!=", "b", "c", "<", "d", "e", ">", "f"]
+        );
+        assert_eq!(
+            tokenize(r#"name = "say \"hi\" \\ ok""#).unwrap(),
+            ["name", "=", r#""say "hi" \ ok"#]
+        );
+        assert!(tokenize("  ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn tokenize_rejects_unterminated_strings() {
+        assert_eq!(
+            tokenize(r#"name = "open"#).unwrap_err(),
+            "Unterminated string"
+        );
+        assert_eq!(
+            tokenize(r#"name = "ends in \""#).unwrap_err(),
+            "Unterminated string"
+        );
+    }
+
+    #[test]
+    fn parse_filter_reads_typed_conditions() {
+        let conditions = parse_filter(
+            &LISTING,
+            r#"price >= 10.5 and created_at < "2024-01-01T00:00:00Z" AND status = open AND name != "a=b""#,
+        )
+        .unwrap();
+
+        assert_eq!(conditions.len(), 4);
+        assert!(matches!(
+            &conditions[0],
+            Condition { column: "price", op: Op::Ge, value: Value::Decimal(price) }
+                if *price == Decimal::new(105, 1)
+        ));
+        assert!(matches!(
+            &conditions[1],
+            Condition { column: "created_at", op: Op::Lt, value: Value::Timestamp(at) }
+                if at.unix_timestamp() == 1_704_067_200
+        ));
+        assert!(matches!(
+            &conditions[2],
+            Condition { column: "status", op: Op::Eq, value: Value::Text(status) }
+                if status == "open"
+        ));
+        assert!(matches!(
+            &conditions[3],
+            Condition { column: "things.name", op: Op::Ne, value: Value::Text(name) }
+                if name == "a=b"
+        ));
+
+        assert!(parse_filter(&LISTING, "").unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_filter_rejects_malformed_input() {
+        for (filter, error) in [
+            ("colour = red", "Unknown field `colour`"),
+            ("price is 10", "Unknown operator `is`"),
+            ("price ! 10", "Unknown operator `!`"),
+            ("thing_id = ten", "Invalid value `ten` for `thing_id`"),
+            ("active = yes", "Invalid value `yes` for `active`"),
+            ("price > 1e", "Invalid value `1e` for `price`"),
+            (
+                r#"created_at > "yesterday""#,
+                "Invalid value `yesterday` for `created_at`",
+            ),
+            ("status = lost", "Invalid value `lost` for `status`"),
+            (
+                "price >=",
+                "Expected `field operator value` between ANDs, got `price >=`",
+            ),
+            (
+                "price > 1 AND",
+                "Expected `field operator value` between ANDs, got ``",
+            ),
+            (
+                "price > 1 thing_id = 2",
+                "Expected `field operator value` between ANDs, got `price > 1 thing_id = 2`",
+            ),
+            (r#"name = "open"#, "Unterminated string"),
+        ] {
+            assert_eq!(
+                parse_filter(&LISTING, filter).err().as_deref(),
+                Some(error),
+                "{}",
+                filter
+            );
+        }
+    }
+
+    #[test]
+    fn parse_order_reads_field_and_direction() {
+        for (order_by, name, descending) in [
+            ("", "thing_id", false),
+            ("price", "price", false),
+            ("price asc", "price", false),
+            ("created_at DESC", "created_at", true),
+            ("  name   desc ", "name", true),
+        ] {
+            let (field, desc) = parse_order(&LISTING, order_by).unwrap();
+            assert_eq!((field.name, desc), (name, descending), "{}", order_by);
+        }
+    }
+
+    #[test]
+    fn parse_order_rejects_unsortable_and_malformed_input() {
+        for (order_by, error) in [
+            ("active", "Can't sort by `active`"),
+            ("colour desc", "Can't sort by `colour`"),
+            (
+                "price up",
+                "Must be a field name, optionally followed by asc or desc",
+            ),
+            (
+                "price asc thing_id",
+                "Must be a field name, optionally followed by asc or desc",
+            ),
+        ] {
+            assert_eq!(
+                parse_order(&LISTING, order_by).err().as_deref(),
+                Some(error),
+                "{}",
+                order_by
+            );
+        }
+    }
+
+    #[test]
+    fn cursor_round_trips_for_its_query() {
+        let query = query_digest(&[], &request("price > 1", "price desc"));
+        let cursor = Cursor {
+            key: "10.5000".to_owned(),
+            id: 42,
+            query: query.clone(),
+        };
+
+        let decoded = Cursor::decode(&cursor.encode(), &query).unwrap();
+
+        assert_eq!((decoded.key.as_str(), decoded.id), ("10.5000", 42));
+    }
+
+    #[test]
+    fn cursor_is_rejected_for_another_query() {
+        let query = query_digest(&[], &request("", ""));
+        let page_token = Cursor {
+            key: "1".to_owned(),
+            id: 1,
+            query,
+        }
+        .encode();
+
+        let other = query_digest(&[], &request("price > 1", ""));
+        assert!(Cursor::decode(&page_token, &other).is_none());
+        assert!(Cursor::decode("not a token!", &other).is_none());
+        assert!(Cursor::decode(&URL_SAFE_NO_PAD.encode("{}"), &other).is_none());
+    }
+
+    #[test]
+    fn query_digest_covers_scope_filter_and_order() {
+        let user = |user_id| vec![Condition::eq("user_id", Value::Int(user_id))];
+        let digest = query_digest(&user(1), &request("price > 1", "price"));
+
+        assert_eq!(
+            digest,
+            query_digest(
+                &user(1),
+                &proto::GetProductsRequest {
+                    page_size: 10,
+                    page_token: "next".to_owned(),
+                    ..request("price > 1", "price")
+                }
+            )
+        );
+        assert_ne!(
+            digest,
+            query_digest(&user(2), &request("price > 1", "price"))
+        );
+        assert_ne!(digest, query_digest(&[], &request("price > 1", "price")));
+        assert_ne!(
+            digest,
+            query_digest(&user(1), &request("price > 2", "price"))
+        );
+        assert_ne!(
+            digest,
+            query_digest(&user(1), &request("price > 1", "price desc"))
+        );
+    }
+}
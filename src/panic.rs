@@ -0,0 +1,16 @@
+use std::{any::Any, backtrace::Backtrace};
+use tonic::{body::BoxBody, codegen::http};
+
+/// Logs every panic with a backtrace through `tracing`, so it lands in the
+/// `rpc` span of the request that caused it.
+pub(crate) fn install_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let backtrace = Backtrace::force_capture();
+        tracing::error!(panic = %info, %backtrace, "Panicked");
+    }));
+}
+
+/// Response sent by the `CatchPanicLayer` in place of a panicked handler's.
+pub(crate) fn response(_: Box<dyn Any + Send + 'static>) -> http::Response<BoxBody> {
+    tonic::Status::internal("Internal Server Error").to_http()
+}
@@ -0,0 +1,142 @@
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2, Params,
+};
+use once_cell::sync::Lazy;
+
+// Verified against when the account does not exist so that unknown usernames
+// take as long to reject as wrong passwords.
+static DUMMY_HASH: Lazy<String> = Lazy::new(|| hash_blocking("dummy password").unwrap());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Verification {
+    Invalid,
+    Valid { needs_rehash: bool },
+}
+
+fn hash_blocking(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut rand::rngs::OsRng);
+
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+fn verify_blocking(password: &str, stored: &str) -> Verification {
+    let Ok(parsed) = PasswordHash::new(stored) else {
+        // Rows written before hashing was introduced hold the plaintext password.
+        return match constant_time_eq(password.as_bytes(), stored.as_bytes()) {
+            true => Verification::Valid { needs_rehash: true },
+            false => Verification::Invalid,
+        };
+    };
+
+    if Argon2::default()
+        .verify_password(password.as_bytes(), &parsed)
+        .is_err()
+    {
+        return Verification::Invalid;
+    }
+
+    let current = Params::default();
+    let needs_rehash = parsed.algorithm != argon2::Algorithm::default().ident()
+        || Params::try_from(&parsed).map_or(true, |params| {
+            (params.m_cost(), params.t_cost(), params.p_cost())
+                != (current.m_cost(), current.t_cost(), current.p_cost())
+        });
+
+    Verification::Valid { needs_rehash }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub(crate) async fn hash(password: &str) -> Result<String, tonic::Status> {
+    let password = password.to_owned();
+
+    tokio::task::spawn_blocking(move || hash_blocking(&password))
+        .await
+        .map_err(|e| {
+            tracing::error!(error = ?e, "Internal error");
+            tonic::Status::internal("Internal Server Error")
+        })?
+        .map_err(|e| {
+            tracing::error!(error = ?e, "Internal error");
+            tonic::Status::internal("Internal Server Error")
+        })
+}
+
+/// Checks `password` against the stored PHC string (or legacy plaintext),
+/// using a dummy hash when no account was found.
+pub(crate) async fn verify(
+    password: &str,
+    stored: Option<&str>,
+) -> Result<Verification, tonic::Status> {
+    let password = password.to_owned();
+    let stored = stored.map(str::to_owned);
+
+    tokio::task::spawn_blocking(move || match stored {
+        Some(stored) => verify_blocking(&password, &stored),
+        None => {
+            verify_blocking(&password, &DUMMY_HASH);
+            Verification::Invalid
+        }
+    })
+    .await
+    .map_err(|e| {
+        tracing::error!(error = ?e, "Internal error");
+        tonic::Status::internal("Internal Server Error")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn current_hashes_verify_without_a_rehash() {
+        let stored = hash_blocking("hunter2").unwrap();
+
+        assert_eq!(
+            verify_blocking("hunter2", &stored),
+            Verification::Valid {
+                needs_rehash: false
+            }
+        );
+        assert_eq!(verify_blocking("hunter3", &stored), Verification::Invalid);
+    }
+
+    #[test]
+    fn hashes_with_other_params_need_a_rehash() {
+        let salt = SaltString::generate(&mut rand::rngs::OsRng);
+        let params = Params::new(8 * 1024, 1, 1, None).unwrap();
+        let stored = Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+            .hash_password(b"hunter2", &salt)
+            .unwrap()
+            .to_string();
+
+        assert_eq!(
+            verify_blocking("hunter2", &stored),
+            Verification::Valid { needs_rehash: true }
+        );
+    }
+
+    #[test]
+    fn legacy_plaintext_verifies_and_needs_a_rehash() {
+        assert_eq!(
+            verify_blocking("hunter2", "hunter2"),
+            Verification::Valid { needs_rehash: true }
+        );
+        assert_eq!(verify_blocking("hunter2", "hunter"), Verification::Invalid);
+        assert_eq!(verify_blocking("", "hunter2"), Verification::Invalid);
+    }
+
+    #[tokio::test]
+    async fn unknown_accounts_never_verify() {
+        assert_eq!(
+            verify("dummy password", None).await.unwrap(),
+            Verification::Invalid
+        );
+    }
+}
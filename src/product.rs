@@ -0,0 +1,107 @@
+use rust_decimal::Decimal;
+use sqlx::query_scalar;
+use sqlx::types::time::OffsetDateTime;
+
+use crate::error::{self, DbError};
+use crate::page::{Field, Kind, Listing};
+use crate::{money, proto, timestamp};
+
+/// A `products` row.
+#[derive(Debug, sqlx::FromRow)]
+pub(crate) struct ProductRow {
+    pub(crate) product_id: i32,
+    pub(crate) name: String,
+    pub(crate) description: String,
+    pub(crate) price: Decimal,
+    pub(crate) currency_code: String,
+    pub(crate) created_at: OffsetDateTime,
+    pub(crate) updated_at: OffsetDateTime,
+    pub(crate) version: i32,
+}
+
+const FIELDS: &[Field] = &[
+    Field {
+        name: "product_id",
+        column: "products.product_id",
+        kind: Kind::Int,
+        sortable: true,
+    },
+    Field {
+        name: "name",
+        column: "products.name",
+        kind: Kind::Text,
+        sortable: true,
+    },
+    Field {
+        name: "description",
+        column: "products.description",
+        kind: Kind::Text,
+        sortable: false,
+    },
+    Field {
+        name: "price",
+        column: "products.price",
+        kind: Kind::Decimal,
+        sortable: true,
+    },
+    Field {
+        name: "currency_code",
+        column: "products.currency_code",
+        kind: Kind::Text,
+        sortable: false,
+    },
+    Field {
+        name: "created_at",
+        column: "products.created_at",
+        kind: Kind::Timestamp,
+        sortable: true,
+    },
+    Field {
+        name: "updated_at",
+        column: "products.updated_at",
+        kind: Kind::Timestamp,
+        sortable: true,
+    },
+];
+
+pub(crate) const LISTING: Listing = Listing {
+    select: "products.*",
+    from: "products",
+    id: "products.product_id",
+    fields: FIELDS,
+    default_order: "product_id",
+};
+
+/// Products in carts, to be scoped to one by `cart_items.user_id`.
+pub(crate) const CART_LISTING: Listing = Listing {
+    from: "products JOIN cart_items ON cart_items.product_id = products.product_id",
+    ..LISTING
+};
+
+impl From<ProductRow> for proto::Product {
+    fn from(row: ProductRow) -> Self {
+        Self {
+            price: Some(money::to_proto(row.price, &row.currency_code)),
+            product_id: row.product_id,
+            name: row.name,
+            description: row.description,
+            created_at: Some(timestamp::to_proto(row.created_at)),
+            updated_at: Some(timestamp::to_proto(row.updated_at)),
+            version: row.version,
+        }
+    }
+}
+
+/// Why a write conditioned on an expected version matched no product.
+pub(crate) async fn version_mismatch(db_pool: &sqlx::PgPool, product_id: i32) -> tonic::Status {
+    match query_scalar!(
+        "SELECT version FROM products WHERE product_id = $1;",
+        product_id
+    )
+    .fetch_one(db_pool)
+    .await
+    {
+        Ok(version) => error::version_mismatch(version),
+        Err(e) => DbError::from(e).into(),
+    }
+}
@@ -0,0 +1,203 @@
+use sqlx::query_scalar;
+
+use crate::error::DbError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Permission {
+    CatalogRead,
+    CatalogWrite,
+    OrdersRead,
+    OrdersWrite,
+    UsersRead,
+    UsersWrite,
+    AdminsRead,
+    AdminsWrite,
+}
+
+impl Permission {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Permission::CatalogRead => "catalog:read",
+            Permission::CatalogWrite => "catalog:write",
+            Permission::OrdersRead => "orders:read",
+            Permission::OrdersWrite => "orders:write",
+            Permission::UsersRead => "users:read",
+            Permission::UsersWrite => "users:write",
+            Permission::AdminsRead => "admins:read",
+            Permission::AdminsWrite => "admins:write",
+        }
+    }
+}
+
+pub(crate) const OWNER_ROLE: &str = "owner";
+
+/// Permission required by each `Admin` RPC. `None` means any authenticated
+/// admin may call it; methods missing from the table are always denied.
+const ADMIN_PERMISSIONS: &[(&str, Option<Permission>)] = &[
+    // Products
+    ("GetProducts", Some(Permission::CatalogRead)),
+    ("GetProduct", Some(Permission::CatalogRead)),
+    ("CreateProduct", Some(Permission::CatalogWrite)),
+    ("UpdateProduct", Some(Permission::CatalogWrite)),
+    ("DeleteProduct", Some(Permission::CatalogWrite)),
+    // Inventory
+    ("GetStockLevels", Some(Permission::CatalogRead)),
+    ("AdjustStock", Some(Permission::CatalogWrite)),
+    ("ListInventoryMovements", Some(Permission::CatalogRead)),
+    ("CheckInventory", Some(Permission::CatalogRead)),
+    // Orders
+    ("GetOrders", Some(Permission::OrdersRead)),
+    ("GetOrder", Some(Permission::OrdersRead)),
+    ("UpdateOrder", Some(Permission::OrdersWrite)),
+    ("TransitionOrder", Some(Permission::OrdersWrite)),
+    ("GetOrderStatusHistory", Some(Permission::OrdersRead)),
+    ("DeleteOrder", Some(Permission::OrdersWrite)),
+    // Admin Accounts
+    ("GetAdminAccounts", Some(Permission::AdminsRead)),
+    ("GetAdminAccount", Some(Permission::AdminsRead)),
+    ("CreateAdminAccount", Some(Permission::AdminsWrite)),
+    ("DeleteAdminAccount", Some(Permission::AdminsWrite)),
+    ("GetAdminRoles", Some(Permission::AdminsRead)),
+    ("AssignAdminRole", Some(Permission::AdminsWrite)),
+    ("RevokeAdminRole", Some(Permission::AdminsWrite)),
+    // Admin Account
+    ("UpdateAdminAccount", None),
+    // Sessions
+    ("ListSessions", None),
+    ("RevokeSession", None),
+    // Two-Factor Authentication
+    ("EnrollTotp", None),
+    ("ConfirmTotp", None),
+    ("DisableTotp", None),
+    // User Accounts
+    ("GetUserAccounts", Some(Permission::UsersRead)),
+    ("GetUserAccount", Some(Permission::UsersRead)),
+    ("CreateUserAccount", Some(Permission::UsersWrite)),
+    ("DeleteUserAccount", Some(Permission::UsersWrite)),
+    ("GetProductsByUser", Some(Permission::UsersRead)),
+    ("GetOrdersByUser", Some(Permission::UsersRead)),
+];
+
+pub(crate) async fn authorize(
+    db_pool: &sqlx::PgPool,
+    admin_id: i32,
+    method: &str,
+) -> Result<(), tonic::Status> {
+    let Some((_, permission)) = ADMIN_PERMISSIONS.iter().find(|(name, _)| *name == method) else {
+        return Err(tonic::Status::permission_denied(format!(
+            "No permission is defined for {}",
+            method
+        )));
+    };
+
+    let Some(permission) = permission else {
+        return Ok(());
+    };
+
+    let granted = query_scalar!(
+        r#"SELECT EXISTS (
+            SELECT 1 FROM admin_roles
+            JOIN role_permissions ON role_permissions.role = admin_roles.role
+            WHERE admin_roles.admin_id = $1 AND role_permissions.permission = $2
+        ) AS "granted!";"#,
+        admin_id,
+        permission.as_str()
+    )
+    .fetch_one(db_pool)
+    .await
+    .map_err(DbError::from)?;
+
+    match granted {
+        true => Ok(()),
+        false => Err(tonic::Status::permission_denied(format!(
+            "Missing permission {}",
+            permission.as_str()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use sqlx::query;
+    use tonic::Code;
+
+    async fn grant(db_pool: &sqlx::PgPool, admin_id: i32, role: &str) {
+        query!(
+            "INSERT INTO admin_roles (admin_id, role) VALUES ($1, $2);",
+            admin_id,
+            role
+        )
+        .execute(db_pool)
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    fn every_admin_rpc_has_a_permission() {
+        let proto = include_str!("../proto/rust_ecom.proto");
+        let service = proto.split("service Admin {").nth(1).unwrap();
+        let service = &service[..service.find("\n}").unwrap()];
+
+        let methods: Vec<&str> = service
+            .split("rpc ")
+            .skip(1)
+            .map(|rpc| &rpc[..rpc.find('(').unwrap()])
+            .collect();
+
+        assert!(!methods.is_empty());
+        for method in methods {
+            assert!(
+                ADMIN_PERMISSIONS.iter().any(|(name, _)| *name == method),
+                "{}",
+                method
+            );
+        }
+    }
+
+    #[sqlx::test]
+    async fn unlisted_methods_are_denied_to_owners(db_pool: sqlx::PgPool) {
+        let admin_id = fixtures::admin(&db_pool, "owner").await;
+        grant(&db_pool, admin_id, OWNER_ROLE).await;
+
+        let status = authorize(&db_pool, admin_id, "DropDatabase")
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+        assert_eq!(
+            status.message(),
+            "No permission is defined for DropDatabase"
+        );
+    }
+
+    #[sqlx::test]
+    async fn roles_grant_only_their_permissions(db_pool: sqlx::PgPool) {
+        let admin_id = fixtures::admin(&db_pool, "catalog").await;
+        grant(&db_pool, admin_id, "catalog_manager").await;
+
+        authorize(&db_pool, admin_id, "CreateProduct")
+            .await
+            .unwrap();
+
+        let status = authorize(&db_pool, admin_id, "GetOrders")
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+        assert_eq!(status.message(), "Missing permission orders:read");
+    }
+
+    #[sqlx::test]
+    async fn admins_without_roles_only_manage_themselves(db_pool: sqlx::PgPool) {
+        let admin_id = fixtures::admin(&db_pool, "nobody").await;
+
+        authorize(&db_pool, admin_id, "UpdateAdminAccount")
+            .await
+            .unwrap();
+
+        let status = authorize(&db_pool, admin_id, "GetProducts")
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+    }
+}
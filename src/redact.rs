@@ -0,0 +1,75 @@
+use std::fmt;
+
+use crate::proto;
+
+/// Metadata keys whose values never appear in logs.
+const SENSITIVE_METADATA: &[&str] = &["authorization", "cookie"];
+
+/// Marks credential-bearing metadata as sensitive so its `Debug` output reads
+/// `Sensitive` instead of the value. Installed for every service in `main`.
+pub(crate) fn sensitive_metadata(
+    mut request: tonic::Request<()>,
+) -> Result<tonic::Request<()>, tonic::Status> {
+    for key in SENSITIVE_METADATA {
+        if let Some(value) = request.metadata_mut().get_mut(*key) {
+            value.set_sensitive(true);
+        }
+    }
+
+    Ok(request)
+}
+
+struct Redacted;
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+/// Implements `Debug` for messages listed in `REDACTED_MESSAGES` in build.rs.
+/// Every field must be named, so a field added to the proto fails to compile
+/// until it is sorted into the printed or the redacted list.
+macro_rules! redacted_debug {
+    ($($message:ident { $($field:ident),* } redact { $($secret:ident),* })*) => {$(
+        impl fmt::Debug for proto::$message {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                let proto::$message { $($field,)* $($secret: _,)* } = self;
+
+                f.debug_struct(stringify!($message))
+                    $(.field(stringify!($field), $field))*
+                    $(.field(stringify!($secret), &Redacted))*
+                    .finish()
+            }
+        }
+    )*};
+}
+
+redacted_debug! {
+    AdminAccount {
+        admin_id, username, email, created_at, updated_at, version
+    } redact { password }
+    UserAccount {
+        user_id, username, email, created_at, updated_at, orders, email_verified, version
+    } redact { password }
+    CreateAdminAccountRequest { username, email } redact { password }
+    UpdateAdminAccountRequest {
+        admin_id, username, email, update_mask, expected_version
+    } redact { password }
+    CreateUserAccountRequest { username, email } redact { password }
+    UpdateUserAccountRequest {
+        user_id, username, email, update_mask, expected_version
+    } redact { password }
+    LoginRequest { username } redact { password }
+    LoginResponse {
+        token_type, expires_in, session_id, totp_required, totp_enrollment_required
+    } redact { access_token, refresh_token, totp_challenge }
+    VerifyAdminTotpRequest {} redact { totp_challenge, code }
+    EnrollTotpResponse {} redact { secret, otpauth_uri }
+    ConfirmTotpRequest {} redact { code }
+    ConfirmTotpResponse {} redact { recovery_codes }
+    DisableTotpRequest {} redact { code }
+    RefreshTokenRequest {} redact { refresh_token }
+    ConfirmPasswordResetRequest {} redact { token, new_password }
+    VerifyEmailRequest {} redact { token }
+}
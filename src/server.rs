@@ -1,14 +1,26 @@
-use sqlx::{query, query_as};
-use std::{sync::Arc, time};
-
+use sqlx::{query, query_as, query_scalar};
+use std::sync::Arc;
+use tonic::Code;
+
+use crate::account::{self, AdminRow, Purpose, UserRow};
+use crate::auth::{self, Principal};
+use crate::error::{self, DbError};
+use crate::mailer::Mailer;
+use crate::mask::Update;
+use crate::order::{self, OrderRow};
+use crate::page::{self, Condition, Page, Value};
+use crate::password::{self, Verification};
+use crate::product::{self, ProductRow};
 use crate::proto::{
     self, admin_server::Admin, storefront_server::Storefront, user_server::User,
-    GetAdminAccountResponse, GetUserAccountResponse,
+    GetAdminAccountResponse, GetUserAccountResponse, OrderStatus,
 };
+use crate::{cart, inventory, money, rbac, session, timestamp, totp};
 
 #[derive(Debug)]
 pub(crate) struct StorefrontService {
     db_pool: Arc<sqlx::PgPool>,
+    mailer: Arc<dyn Mailer>,
 }
 
 #[derive(Debug)]
@@ -19,11 +31,12 @@ pub(crate) struct AdminService {
 #[derive(Debug)]
 pub(crate) struct UserService {
     db_pool: Arc<sqlx::PgPool>,
+    mailer: Arc<dyn Mailer>,
 }
 
 impl StorefrontService {
-    pub(crate) fn new(db_pool: Arc<sqlx::PgPool>) -> Self {
-        Self { db_pool }
+    pub(crate) fn new(db_pool: Arc<sqlx::PgPool>, mailer: Arc<dyn Mailer>) -> Self {
+        Self { db_pool, mailer }
     }
 }
 
@@ -31,11 +44,54 @@ impl AdminService {
     pub(crate) fn new(db_pool: Arc<sqlx::PgPool>) -> Self {
         Self { db_pool }
     }
+
+    async fn admin_roles(&self, admin_id: i32) -> Result<Vec<String>, tonic::Status> {
+        query_scalar!(
+            "SELECT role FROM admin_roles WHERE admin_id = $1 ORDER BY role;",
+            admin_id
+        )
+        .fetch_all(self.db_pool.as_ref())
+        .await
+        .map_err(|e| DbError::from(e).into())
+    }
+
+    /// Refuses changes that would leave nobody able to manage admin roles:
+    /// fails unless removing `admin_id` as an owner leaves another one. Locks
+    /// `admin_roles` until `tx` ends, so concurrent removals can't both pass.
+    async fn ensure_other_owner(
+        tx: &mut sqlx::PgConnection,
+        admin_id: i32,
+    ) -> Result<(), tonic::Status> {
+        query!("LOCK TABLE admin_roles IN SHARE ROW EXCLUSIVE MODE;")
+            .execute(&mut *tx)
+            .await
+            .map_err(DbError::from)?;
+
+        let allowed = query_scalar!(
+            r#"SELECT NOT EXISTS (
+                SELECT 1 FROM admin_roles WHERE admin_id = $1 AND role = $2
+            ) OR EXISTS (
+                SELECT 1 FROM admin_roles WHERE admin_id <> $1 AND role = $2
+            ) AS "allowed!";"#,
+            admin_id,
+            rbac::OWNER_ROLE
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(DbError::from)?;
+
+        match allowed {
+            true => Ok(()),
+            false => Err(tonic::Status::failed_precondition(
+                "Cannot remove the last owner",
+            )),
+        }
+    }
 }
 
 impl UserService {
-    pub(crate) fn new(db_pool: Arc<sqlx::PgPool>) -> Self {
-        Self { db_pool }
+    pub(crate) fn new(db_pool: Arc<sqlx::PgPool>, mailer: Arc<dyn Mailer>) -> Self {
+        Self { db_pool, mailer }
     }
 }
 
@@ -43,91 +99,358 @@ impl UserService {
 impl Storefront for StorefrontService {
     async fn get_products(
         &self,
-        request: tonic::Request<proto::Empty>,
+        request: tonic::Request<proto::GetProductsRequest>,
     ) -> Result<tonic::Response<proto::GetProductsResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
-        let res = query_as!(proto::Product, "SELECT * FROM products;")
-            .fetch_all(self.db_pool.as_ref())
-            .await
-            .map_err(|e| {
-                println!("ERROR: {:?}", e);
-                tonic::Status::internal("Internal Server Error")
-            })?;
+        let page: Page<ProductRow> = page::fetch(
+            self.db_pool.as_ref(),
+            &product::LISTING,
+            request.get_ref(),
+            vec![],
+        )
+        .await?;
+        let res: Vec<proto::Product> = page.items.into_iter().map(Into::into).collect();
 
         res.iter().for_each(|product| {
-            println!("Product: {:?}", product);
+            tracing::debug!(product = ?product);
         });
 
-        let response = proto::GetProductsResponse { products: res };
+        let response = proto::GetProductsResponse {
+            products: res,
+            next_page_token: page.next_page_token,
+        };
 
         Ok(tonic::Response::new(response))
-        // Err(tonic::Status::unimplemented("Not yet implemented"))
     }
 
     async fn get_product(
         &self,
         request: tonic::Request<proto::GetProductRequest>,
     ) -> Result<tonic::Response<proto::GetProductResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
-        let res = query_as!(
-            proto::Product,
+        let res: proto::Product = query_as!(
+            ProductRow,
             "SELECT * FROM products WHERE product_id = $1;",
             request.get_ref().product_id
         )
         .fetch_one(self.db_pool.as_ref())
         .await
-        .map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        .map_err(DbError::from)?
+        .into();
 
-        println!("Product: {:?}", res);
+        tracing::debug!(product = ?res);
 
         let response = proto::GetProductResponse { product: Some(res) };
 
         Ok(tonic::Response::new(response))
-        // Err(tonic::Status::unimplemented("Not yet implemented"))
     }
 
     async fn create_user_account(
         &self,
         request: tonic::Request<proto::CreateUserAccountRequest>,
     ) -> Result<tonic::Response<proto::CreateUserAccountResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
         let request = request.get_ref();
-
-        let res = query_as!(
-            proto::UserAccount,
-            "INSERT INTO users (username, password, email, created_at, products, orders) VALUES ($1, $2, $3, $4, $5, $6) RETURNING *;",
-            request.username,
-            request.password,
-            request.email,
-            time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap().as_secs() as f64,
-            &vec![],
+        let password_hash = password::hash(&request.password).await?;
+
+        // The account and its verification token are created together, so the
+        // account can't be left without a way to verify it.
+        let mut tx = self.db_pool.begin().await.map_err(DbError::from)?;
+
+        let res: proto::UserAccount = query_as!(
+            UserRow,
+            r#"INSERT INTO users (username, password, email, orders)
+                VALUES ($1, $2, $3, $4)
+                RETURNING *;"#,
+            account::normalize_username(&request.username),
+            password_hash,
+            account::normalize_email(&request.email),
             &vec![]
-        ).fetch_one(self.db_pool.as_ref()).await.map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(DbError::from)?
+        .into();
+
+        let token =
+            account::issue_token(&mut tx, Purpose::EmailVerification, res.user_id, &res.email)
+                .await?;
 
-        println!("User Account: {:?}", res);
+        tx.commit().await.map_err(DbError::from)?;
+
+        tracing::debug!(user_account = ?res);
+
+        // A failed delivery is logged; updating the account mails a fresh code.
+        account::mail_token(
+            self.mailer.as_ref(),
+            Purpose::EmailVerification,
+            res.user_id,
+            &res.email,
+            &token,
+        )
+        .await;
 
         let response = proto::CreateUserAccountResponse {
             account: Some(GetUserAccountResponse {
                 user_id: res.user_id,
                 username: res.username,
                 email: res.email,
+                email_verified: res.email_verified,
                 created_at: res.created_at,
-                products: vec![],
+                updated_at: res.updated_at,
+                version: res.version,
                 orders: vec![],
             }),
         };
 
         Ok(tonic::Response::new(response))
-        // Err(tonic::Status::unimplemented("Not yet implemented"))
+    }
+
+    async fn login(
+        &self,
+        request: tonic::Request<proto::LoginRequest>,
+    ) -> Result<tonic::Response<proto::LoginResponse>, tonic::Status> {
+        tracing::debug!(request = ?request.get_ref(), "Received request");
+
+        let request = request.get_ref();
+
+        let res = query!(
+            "SELECT user_id, password FROM users WHERE lower(username) = lower($1);",
+            account::normalize_username(&request.username)
+        )
+        .fetch_optional(self.db_pool.as_ref())
+        .await
+        .map_err(DbError::from)?;
+
+        let verification = password::verify(
+            &request.password,
+            res.as_ref().map(|row| row.password.as_str()),
+        )
+        .await?;
+
+        let (Some(res), Verification::Valid { needs_rehash }) = (res, verification) else {
+            return Err(tonic::Status::unauthenticated(
+                "Invalid username or password",
+            ));
+        };
+
+        if needs_rehash {
+            let mut tx = self.db_pool.begin().await.map_err(DbError::from)?;
+
+            // A rehash isn't an edit of the account, so it keeps its version.
+            query!("SET LOCAL app.rehash = 'on';")
+                .execute(&mut *tx)
+                .await
+                .map_err(DbError::from)?;
+
+            query!(
+                "UPDATE users SET password = $1 WHERE user_id = $2;",
+                password::hash(&request.password).await?,
+                res.user_id
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(DbError::from)?;
+
+            tx.commit().await.map_err(DbError::from)?;
+        }
+
+        let response = session::start(self.db_pool.as_ref(), Principal::User(res.user_id)).await?;
+
+        Ok(tonic::Response::new(response))
+    }
+
+    async fn admin_login(
+        &self,
+        request: tonic::Request<proto::LoginRequest>,
+    ) -> Result<tonic::Response<proto::LoginResponse>, tonic::Status> {
+        tracing::debug!(request = ?request.get_ref(), "Received request");
+
+        let request = request.get_ref();
+
+        let res = query!(
+            "SELECT admin_id, password FROM admins WHERE lower(username) = lower($1);",
+            account::normalize_username(&request.username)
+        )
+        .fetch_optional(self.db_pool.as_ref())
+        .await
+        .map_err(DbError::from)?;
+
+        let verification = password::verify(
+            &request.password,
+            res.as_ref().map(|row| row.password.as_str()),
+        )
+        .await?;
+
+        let (Some(res), Verification::Valid { needs_rehash }) = (res, verification) else {
+            return Err(tonic::Status::unauthenticated(
+                "Invalid username or password",
+            ));
+        };
+
+        if needs_rehash {
+            let mut tx = self.db_pool.begin().await.map_err(DbError::from)?;
+
+            // A rehash isn't an edit of the account, so it keeps its version.
+            query!("SET LOCAL app.rehash = 'on';")
+                .execute(&mut *tx)
+                .await
+                .map_err(DbError::from)?;
+
+            query!(
+                "UPDATE admins SET password = $1 WHERE admin_id = $2;",
+                password::hash(&request.password).await?,
+                res.admin_id
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(DbError::from)?;
+
+            tx.commit().await.map_err(DbError::from)?;
+        }
+
+        if totp::is_enabled(self.db_pool.as_ref(), res.admin_id).await? {
+            let response = proto::LoginResponse {
+                totp_required: true,
+                totp_challenge: auth::issue_totp_challenge(self.db_pool.as_ref(), res.admin_id)
+                    .await?,
+                ..Default::default()
+            };
+
+            return Ok(tonic::Response::new(response));
+        }
+
+        let response =
+            session::start(self.db_pool.as_ref(), Principal::Admin(res.admin_id)).await?;
+
+        Ok(tonic::Response::new(response))
+    }
+
+    async fn verify_admin_totp(
+        &self,
+        request: tonic::Request<proto::VerifyAdminTotpRequest>,
+    ) -> Result<tonic::Response<proto::LoginResponse>, tonic::Status> {
+        tracing::debug!(request = ?request.get_ref(), "Received request");
+
+        let request = request.get_ref();
+
+        let admin_id =
+            auth::verify_totp_challenge(self.db_pool.as_ref(), &request.totp_challenge).await?;
+
+        if !totp::check(self.db_pool.as_ref(), admin_id, &request.code).await? {
+            return Err(tonic::Status::unauthenticated("Invalid TOTP code"));
+        }
+
+        let response = session::start(self.db_pool.as_ref(), Principal::Admin(admin_id)).await?;
+
+        Ok(tonic::Response::new(response))
+    }
+
+    async fn refresh_token(
+        &self,
+        request: tonic::Request<proto::RefreshTokenRequest>,
+    ) -> Result<tonic::Response<proto::LoginResponse>, tonic::Status> {
+        tracing::debug!(request = ?request.get_ref(), "Received request");
+
+        let response =
+            session::refresh(self.db_pool.as_ref(), &request.get_ref().refresh_token).await?;
+
+        Ok(tonic::Response::new(response))
+    }
+
+    // Account Recovery
+
+    async fn request_password_reset(
+        &self,
+        request: tonic::Request<proto::RequestPasswordResetRequest>,
+    ) -> Result<tonic::Response<proto::Empty>, tonic::Status> {
+        tracing::debug!(request = ?request.get_ref(), "Received request");
+
+        let request = request.get_ref();
+
+        let email = account::normalize_email(&request.email);
+
+        // Answer the same way whether or not the address is registered.
+        if let Some(user_id) = account::user_with_email(self.db_pool.as_ref(), &email).await? {
+            account::send_token(
+                self.db_pool.as_ref(),
+                self.mailer.as_ref(),
+                Purpose::PasswordReset,
+                user_id,
+                &email,
+            )
+            .await?;
+        }
+
+        Ok(tonic::Response::new(proto::Empty {}))
+    }
+
+    async fn confirm_password_reset(
+        &self,
+        request: tonic::Request<proto::ConfirmPasswordResetRequest>,
+    ) -> Result<tonic::Response<proto::Empty>, tonic::Status> {
+        tracing::debug!(request = ?request.get_ref(), "Received request");
+
+        let request = request.get_ref();
+        let password_hash = password::hash(&request.new_password).await?;
+
+        let mut tx = self.db_pool.begin().await.map_err(DbError::from)?;
+
+        let (user_id, _) =
+            account::consume_token(&mut *tx, Purpose::PasswordReset, &request.token).await?;
+
+        query!(
+            "UPDATE users SET password = $1 WHERE user_id = $2;",
+            password_hash,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(DbError::from)?;
+
+        session::revoke_others(&mut *tx, Principal::User(user_id), None).await?;
+
+        tx.commit().await.map_err(DbError::from)?;
+
+        Ok(tonic::Response::new(proto::Empty {}))
+    }
+
+    async fn verify_email(
+        &self,
+        request: tonic::Request<proto::VerifyEmailRequest>,
+    ) -> Result<tonic::Response<proto::Empty>, tonic::Status> {
+        tracing::debug!(request = ?request.get_ref(), "Received request");
+
+        let mut tx = self.db_pool.begin().await.map_err(DbError::from)?;
+
+        let (user_id, email) = account::consume_token(
+            &mut *tx,
+            Purpose::EmailVerification,
+            &request.get_ref().token,
+        )
+        .await?;
+
+        let res = query!(
+            "UPDATE users SET email_verified = TRUE WHERE user_id = $1 AND email = $2;",
+            user_id,
+            email
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(DbError::from)?;
+
+        if res.rows_affected() == 0 {
+            return Err(tonic::Status::failed_precondition(
+                "Email address has changed since the code was sent",
+            ));
+        }
+
+        tx.commit().await.map_err(DbError::from)?;
+
+        Ok(tonic::Response::new(proto::Empty {}))
     }
 }
 
@@ -137,23 +460,27 @@ impl Admin for AdminService {
 
     async fn get_products(
         &self,
-        request: tonic::Request<proto::Empty>,
+        request: tonic::Request<proto::GetProductsRequest>,
     ) -> Result<tonic::Response<proto::GetProductsResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
-        let res = query_as!(proto::Product, "SELECT * FROM products;")
-            .fetch_all(self.db_pool.as_ref())
-            .await
-            .map_err(|e| {
-                println!("ERROR: {:?}", e);
-                tonic::Status::internal("Internal Server Error")
-            })?;
+        let page: Page<ProductRow> = page::fetch(
+            self.db_pool.as_ref(),
+            &product::LISTING,
+            request.get_ref(),
+            vec![],
+        )
+        .await?;
+        let res: Vec<proto::Product> = page.items.into_iter().map(Into::into).collect();
 
         res.iter().for_each(|product| {
-            println!("Product: {:?}", product);
+            tracing::debug!(product = ?product);
         });
 
-        let response = proto::GetProductsResponse { products: res };
+        let response = proto::GetProductsResponse {
+            products: res,
+            next_page_token: page.next_page_token,
+        };
 
         Ok(tonic::Response::new(response))
     }
@@ -161,21 +488,19 @@ impl Admin for AdminService {
         &self,
         request: tonic::Request<proto::GetProductRequest>,
     ) -> Result<tonic::Response<proto::GetProductResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
-        let res = query_as!(
-            proto::Product,
+        let res: proto::Product = query_as!(
+            ProductRow,
             "SELECT * FROM products WHERE product_id = $1;",
             request.get_ref().product_id
         )
         .fetch_one(self.db_pool.as_ref())
         .await
-        .map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        .map_err(DbError::from)?
+        .into();
 
-        println!("Product: {:?}", res);
+        tracing::debug!(product = ?res);
 
         let response = proto::GetProductResponse { product: Some(res) };
 
@@ -186,26 +511,27 @@ impl Admin for AdminService {
         &self,
         request: tonic::Request<proto::CreateProductRequest>,
     ) -> Result<tonic::Response<proto::CreateProductResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
         let request = request.get_ref();
+        let price = request.price.clone().unwrap_or_default();
 
-        let res = query_as!(
-            proto::Product,
-            "INSERT INTO products (name, description, price, created_at) VALUES ($1, $2, $3, $4) RETURNING *;",
+        let res: proto::Product = query_as!(
+            ProductRow,
+            r#"INSERT INTO products (name, description, price, currency_code)
+                VALUES ($1, $2, $3, $4)
+                RETURNING *;"#,
             request.name,
             request.description,
-            request.price,
-            time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap().as_secs() as f64
+            money::amount(&price),
+            price.currency_code
         )
         .fetch_one(self.db_pool.as_ref())
         .await
-        .map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        .map_err(DbError::from)?
+        .into();
 
-        println!("Product: {:?}", res);
+        tracing::debug!(product = ?res);
 
         let response = proto::CreateProductResponse { product: Some(res) };
 
@@ -216,23 +542,39 @@ impl Admin for AdminService {
         &self,
         request: tonic::Request<proto::UpdateProductRequest>,
     ) -> Result<tonic::Response<proto::UpdateProductResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
         let request = request.get_ref();
+        let price = request.price.as_ref().filter(|_| request.writes("price"));
+
+        let row = query_as!(
+            ProductRow,
+            r#"UPDATE products SET
+                    name = COALESCE($1, name),
+                    description = COALESCE($2, description),
+                    price = COALESCE($3, price),
+                    currency_code = COALESCE($4, currency_code)
+                WHERE product_id = $5 AND ($6 = 0 OR version = $6)
+                RETURNING *;"#,
+            request.writes("name").then_some(&request.name),
+            request
+                .writes("description")
+                .then_some(&request.description),
+            price.map(money::amount),
+            price.map(|price| &price.currency_code),
+            request.product_id,
+            request.expected_version
+        )
+        .fetch_optional(self.db_pool.as_ref())
+        .await
+        .map_err(DbError::from)?;
 
-        let res = query_as!(
-            proto::Product,
-            "UPDATE products SET name = $1, description = $2, price = $3 WHERE product_id = $4 RETURNING *;",
-            request.name,
-            request.description,
-            request.price,
-            request.product_id
-        ).fetch_one(self.db_pool.as_ref()).await.map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        let Some(row) = row else {
+            return Err(product::version_mismatch(self.db_pool.as_ref(), request.product_id).await);
+        };
+        let res: proto::Product = row.into();
 
-        println!("Product: {:?}", res);
+        tracing::debug!(product = ?res);
 
         let response = proto::UpdateProductResponse { product: Some(res) };
 
@@ -243,50 +585,137 @@ impl Admin for AdminService {
         &self,
         request: tonic::Request<proto::DeleteProductRequest>,
     ) -> Result<tonic::Response<proto::DeleteProductResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
         let request = request.get_ref();
 
-        let res = query_as!(
-            proto::Product,
-            "DELETE FROM products WHERE product_id = $1 RETURNING *;",
-            request.product_id
+        let row = query_as!(
+            ProductRow,
+            "DELETE FROM products WHERE product_id = $1 AND ($2 = 0 OR version = $2) RETURNING *;",
+            request.product_id,
+            request.expected_version
         )
-        .fetch_one(self.db_pool.as_ref())
+        .fetch_optional(self.db_pool.as_ref())
         .await
-        .map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        .map_err(DbError::from)?;
 
-        println!("Product: {:?}", res);
+        let Some(row) = row else {
+            return Err(product::version_mismatch(self.db_pool.as_ref(), request.product_id).await);
+        };
+        let res: proto::Product = row.into();
+
+        tracing::debug!(product = ?res);
 
         let response = proto::DeleteProductResponse { product: Some(res) };
 
         Ok(tonic::Response::new(response))
     }
 
+    // Inventory
+
+    async fn get_stock_levels(
+        &self,
+        request: tonic::Request<proto::GetStockLevelsRequest>,
+    ) -> Result<tonic::Response<proto::GetStockLevelsResponse>, tonic::Status> {
+        tracing::debug!(request = ?request.get_ref(), "Received request");
+
+        let levels =
+            inventory::levels(self.db_pool.as_ref(), &request.get_ref().product_ids).await?;
+
+        let response = proto::GetStockLevelsResponse { levels };
+
+        Ok(tonic::Response::new(response))
+    }
+
+    async fn adjust_stock(
+        &self,
+        request: tonic::Request<proto::AdjustStockRequest>,
+    ) -> Result<tonic::Response<proto::AdjustStockResponse>, tonic::Status> {
+        tracing::debug!(request = ?request.get_ref(), "Received request");
+
+        let admin_id = auth::admin_id(&request)?;
+        let request = request.get_ref();
+
+        let level = inventory::adjust(
+            self.db_pool.as_ref(),
+            request.product_id,
+            request.delta,
+            request.kind(),
+            admin_id,
+            &request.note,
+        )
+        .await?;
+
+        tracing::info!(
+            product_id = level.product_id,
+            delta = request.delta,
+            stock_on_hand = level.stock_on_hand,
+            "Stock adjusted"
+        );
+
+        let response = proto::AdjustStockResponse { level: Some(level) };
+
+        Ok(tonic::Response::new(response))
+    }
+
+    async fn list_inventory_movements(
+        &self,
+        request: tonic::Request<proto::ListInventoryMovementsRequest>,
+    ) -> Result<tonic::Response<proto::ListInventoryMovementsResponse>, tonic::Status> {
+        tracing::debug!(request = ?request.get_ref(), "Received request");
+
+        let page = inventory::movements(self.db_pool.as_ref(), request.get_ref()).await?;
+
+        let response = proto::ListInventoryMovementsResponse {
+            movements: page.items,
+            next_page_token: page.next_page_token,
+        };
+
+        Ok(tonic::Response::new(response))
+    }
+
+    async fn check_inventory(
+        &self,
+        request: tonic::Request<proto::Empty>,
+    ) -> Result<tonic::Response<proto::CheckInventoryResponse>, tonic::Status> {
+        tracing::debug!(request = ?request.get_ref(), "Received request");
+
+        let discrepancies = inventory::discrepancies(self.db_pool.as_ref()).await?;
+
+        discrepancies.iter().for_each(|discrepancy| {
+            tracing::warn!(?discrepancy, "Stock level doesn't match the ledger");
+        });
+
+        let response = proto::CheckInventoryResponse { discrepancies };
+
+        Ok(tonic::Response::new(response))
+    }
+
     // Orders
 
     async fn get_orders(
         &self,
-        request: tonic::Request<proto::Empty>,
+        request: tonic::Request<proto::GetOrdersRequest>,
     ) -> Result<tonic::Response<proto::GetOrdersResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
-        let res = query_as!(proto::Order, "SELECT * FROM orders;")
-            .fetch_all(self.db_pool.as_ref())
-            .await
-            .map_err(|e| {
-                println!("ERROR: {:?}", e);
-                tonic::Status::internal("Internal Server Error")
-            })?;
+        let page: Page<OrderRow> = page::fetch(
+            self.db_pool.as_ref(),
+            &order::LISTING,
+            request.get_ref(),
+            vec![],
+        )
+        .await?;
+        let res = order::with_items(self.db_pool.as_ref(), page.items).await?;
 
         res.iter().for_each(|order| {
-            println!("Order: {:?}", order);
+            tracing::debug!(order = ?order);
         });
 
-        let response = proto::GetOrdersResponse { orders: res };
+        let response = proto::GetOrdersResponse {
+            orders: res,
+            next_page_token: page.next_page_token,
+        };
 
         Ok(tonic::Response::new(response))
     }
@@ -294,21 +723,21 @@ impl Admin for AdminService {
         &self,
         request: tonic::Request<proto::GetOrderRequest>,
     ) -> Result<tonic::Response<proto::GetOrderResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
-        let res = query_as!(
-            proto::Order,
+        let row = query_as!(
+            OrderRow,
             "SELECT * FROM orders WHERE order_id = $1;",
             request.get_ref().order_id
         )
         .fetch_one(self.db_pool.as_ref())
         .await
-        .map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        .map_err(DbError::from)?;
+        let res = order::with_items(self.db_pool.as_ref(), vec![row])
+            .await?
+            .remove(0);
 
-        println!("Order: {:?}", res);
+        tracing::debug!(order = ?res);
 
         let response = proto::GetOrderResponse { order: Some(res) };
 
@@ -319,51 +748,148 @@ impl Admin for AdminService {
         &self,
         request: tonic::Request<proto::UpdateOrderRequest>,
     ) -> Result<tonic::Response<proto::UpdateOrderResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
         let request = request.get_ref();
+        let total = request.total.as_ref().filter(|_| request.writes("total"));
+
+        // The items were paid for in the order's currency, so the total stays in it.
+        let row = query_as!(
+            OrderRow,
+            "UPDATE orders SET user_id = COALESCE($1, user_id), total = COALESCE($2, total) WHERE order_id = $3 AND currency_code = COALESCE($4, currency_code) AND ($5 = 0 OR version = $5) RETURNING *;",
+            request.writes("user_id").then_some(request.user_id),
+            total.map(money::amount),
+            request.order_id,
+            total.map(|total| &total.currency_code),
+            request.expected_version
+        )
+        .fetch_optional(self.db_pool.as_ref())
+        .await
+        .map_err(DbError::from)?;
 
-        let res = query_as!(
-            proto::Order,
-            "UPDATE orders SET user_id = $1, products = $2, total = $3, status = $4 WHERE order_id = $5 RETURNING *;",
-            request.user_id,
-            &request.products,
-            request.total,
-            request.status,
-            request.order_id
-        ).fetch_one(self.db_pool.as_ref()).await.map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        let Some(row) = row else {
+            let current = query!(
+                "SELECT currency_code, version FROM orders WHERE order_id = $1;",
+                request.order_id
+            )
+            .fetch_one(self.db_pool.as_ref())
+            .await
+            .map_err(DbError::from)?;
+
+            if request.expected_version != 0 && current.version != request.expected_version {
+                return Err(error::version_mismatch(current.version));
+            }
+
+            return Err(error::with_details(
+                Code::FailedPrecondition,
+                "Order total must be in the order's currency",
+                vec![
+                    error::error_info(
+                        "CURRENCY_MISMATCH",
+                        &[("currency_code", &current.currency_code)],
+                    ),
+                    error::bad_request(&[("total", "Must be in the order's currency")]),
+                ],
+            ));
+        };
+        let res = order::with_items(self.db_pool.as_ref(), vec![row])
+            .await?
+            .remove(0);
 
-        println!("Order: {:?}", res);
+        tracing::debug!(order = ?res);
 
         let response = proto::UpdateOrderResponse { order: Some(res) };
 
         Ok(tonic::Response::new(response))
     }
 
+    async fn transition_order(
+        &self,
+        request: tonic::Request<proto::TransitionOrderRequest>,
+    ) -> Result<tonic::Response<proto::TransitionOrderResponse>, tonic::Status> {
+        tracing::debug!(request = ?request.get_ref(), "Received request");
+
+        let admin_id = auth::admin_id(&request)?;
+        let request = request.get_ref();
+
+        let mut tx = self.db_pool.begin().await.map_err(DbError::from)?;
+
+        let row = order::transition(
+            &mut tx,
+            request.order_id,
+            request.status(),
+            Principal::Admin(admin_id),
+            &request.note,
+        )
+        .await?;
+        let res = order::with_items(&mut *tx, vec![row]).await?.remove(0);
+
+        tx.commit().await.map_err(DbError::from)?;
+
+        tracing::info!(order_id = res.order_id, status = ?res.status(), "Order status changed");
+
+        let response = proto::TransitionOrderResponse { order: Some(res) };
+
+        Ok(tonic::Response::new(response))
+    }
+
+    async fn get_order_status_history(
+        &self,
+        request: tonic::Request<proto::GetOrderRequest>,
+    ) -> Result<tonic::Response<proto::GetOrderStatusHistoryResponse>, tonic::Status> {
+        tracing::debug!(request = ?request.get_ref(), "Received request");
+
+        let order_id = request.get_ref().order_id;
+
+        query_scalar!("SELECT order_id FROM orders WHERE order_id = $1;", order_id)
+            .fetch_optional(self.db_pool.as_ref())
+            .await
+            .map_err(DbError::from)?
+            .ok_or_else(|| tonic::Status::not_found("Order not found"))?;
+
+        let changes = order::status_history(self.db_pool.as_ref(), order_id).await?;
+
+        let response = proto::GetOrderStatusHistoryResponse { changes };
+
+        Ok(tonic::Response::new(response))
+    }
+
     async fn delete_order(
         &self,
         request: tonic::Request<proto::DeleteOrderRequest>,
     ) -> Result<tonic::Response<proto::DeleteOrderResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
         let request = request.get_ref();
 
-        let res = query_as!(
-            proto::Order,
-            "DELETE FROM orders WHERE order_id = $1 RETURNING *;",
+        let mut tx = self.db_pool.begin().await.map_err(DbError::from)?;
+
+        inventory::release(&mut tx, request.order_id).await?;
+
+        // Read the items before the delete cascades to them.
+        let row = query_as!(
+            OrderRow,
+            "SELECT * FROM orders WHERE order_id = $1 FOR UPDATE;",
             request.order_id
         )
-        .fetch_one(self.db_pool.as_ref())
+        .fetch_one(&mut *tx)
         .await
-        .map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        .map_err(DbError::from)?;
+
+        if request.expected_version != 0 && row.version != request.expected_version {
+            return Err(error::version_mismatch(row.version));
+        }
+
+        let res = order::with_items(&mut *tx, vec![row]).await?.remove(0);
 
-        println!("Order: {:?}", res);
+        query!("DELETE FROM orders WHERE order_id = $1;", request.order_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(DbError::from)?;
+
+        tx.commit().await.map_err(DbError::from)?;
+
+        tracing::debug!(order = ?res);
 
         let response = proto::DeleteOrderResponse { order: Some(res) };
 
@@ -374,20 +900,21 @@ impl Admin for AdminService {
 
     async fn get_admin_accounts(
         &self,
-        request: tonic::Request<proto::Empty>,
+        request: tonic::Request<proto::GetAdminAccountsRequest>,
     ) -> Result<tonic::Response<proto::GetAdminAccountsResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
-        let res = query_as!(proto::AdminAccount, "SELECT * FROM admins;")
-            .fetch_all(self.db_pool.as_ref())
-            .await
-            .map_err(|e| {
-                println!("ERROR: {:?}", e);
-                tonic::Status::internal("Internal Server Error")
-            })?;
+        let page: Page<AdminRow> = page::fetch(
+            self.db_pool.as_ref(),
+            &account::ADMIN_LISTING,
+            request.get_ref(),
+            vec![],
+        )
+        .await?;
+        let res: Vec<proto::AdminAccount> = page.items.into_iter().map(Into::into).collect();
 
         res.iter().for_each(|admin| {
-            println!("Admin Account: {:?}", admin);
+            tracing::debug!(admin_account = ?admin);
         });
 
         let response = proto::GetAdminAccountsResponse {
@@ -397,9 +924,12 @@ impl Admin for AdminService {
                     admin_id: admin.admin_id,
                     username: admin.username.to_owned(),
                     email: admin.email.to_owned(),
-                    created_at: admin.created_at,
+                    created_at: admin.created_at.clone(),
+                    updated_at: admin.updated_at.clone(),
+                    version: admin.version,
                 })
                 .collect(),
+            next_page_token: page.next_page_token,
         };
 
         Ok(tonic::Response::new(response))
@@ -408,27 +938,27 @@ impl Admin for AdminService {
         &self,
         request: tonic::Request<proto::GetAdminAccountRequest>,
     ) -> Result<tonic::Response<proto::GetAdminAccountResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
-        let res = query_as!(
-            proto::AdminAccount,
+        let res: proto::AdminAccount = query_as!(
+            AdminRow,
             "SELECT * FROM admins WHERE admin_id = $1;",
             request.get_ref().admin_id
         )
         .fetch_one(self.db_pool.as_ref())
         .await
-        .map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        .map_err(DbError::from)?
+        .into();
 
-        println!("Admin Account: {:?}", res);
+        tracing::debug!(admin_account = ?res);
 
         let response = proto::GetAdminAccountResponse {
             admin_id: res.admin_id,
             username: res.username.to_owned(),
             email: res.email.to_owned(),
             created_at: res.created_at,
+            updated_at: res.updated_at,
+            version: res.version,
         };
 
         Ok(tonic::Response::new(response))
@@ -438,23 +968,23 @@ impl Admin for AdminService {
         &self,
         request: tonic::Request<proto::CreateAdminAccountRequest>,
     ) -> Result<tonic::Response<proto::CreateAdminAccountResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
         let request = request.get_ref();
 
-        let res = query_as!(
-            proto::AdminAccount,
-            "INSERT INTO admins (username, password, email, created_at) VALUES ($1, $2, $3, $4) RETURNING *;",
-            request.username,
-            request.password,
-            request.email,
-            time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap().as_secs() as f64
-        ).fetch_one(self.db_pool.as_ref()).await.map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        let res: proto::AdminAccount = query_as!(
+            AdminRow,
+            "INSERT INTO admins (username, password, email) VALUES ($1, $2, $3) RETURNING *;",
+            account::normalize_username(&request.username),
+            password::hash(&request.password).await?,
+            account::normalize_email(&request.email)
+        )
+        .fetch_one(self.db_pool.as_ref())
+        .await
+        .map_err(DbError::from)?
+        .into();
 
-        println!("Admin Account: {:?}", res);
+        tracing::debug!(admin_account = ?res);
 
         let response = proto::CreateAdminAccountResponse {
             account: Some(GetAdminAccountResponse {
@@ -462,6 +992,8 @@ impl Admin for AdminService {
                 username: res.username.to_owned(),
                 email: res.email.to_owned(),
                 created_at: res.created_at,
+                updated_at: res.updated_at,
+                version: res.version,
             }),
         };
 
@@ -474,32 +1006,55 @@ impl Admin for AdminService {
         &self,
         request: tonic::Request<proto::UpdateAdminAccountRequest>,
     ) -> Result<tonic::Response<proto::UpdateAdminAccountResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
-        let headers = request.metadata();
+        let admin_id = auth::admin_id(&request)?;
+        let session_id = auth::session_id(&request)?;
         let request = request.get_ref();
 
-        let admin_id = headers
-            .get("admin_id")
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .parse::<i32>()
-            .unwrap();
+        let password = match request.writes("password") {
+            true => Some(password::hash(&request.password).await?),
+            false => None,
+        };
 
-        let res = query_as!(
-            proto::AdminAccount,
-            "UPDATE admins SET username = $1, password = $2, email = $3 WHERE admin_id = $4 RETURNING *;",
-            request.username,
-            request.password,
-            request.email,
-            admin_id
-        ).fetch_one(self.db_pool.as_ref()).await.map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        let mut tx = self.db_pool.begin().await.map_err(DbError::from)?;
+
+        let row = query_as!(
+            AdminRow,
+            r#"UPDATE admins SET
+                    username = COALESCE($1, username),
+                    password = COALESCE($2, password),
+                    email = COALESCE($3, email)
+                WHERE admin_id = $4 AND ($5 = 0 OR version = $5)
+                RETURNING *;"#,
+            request
+                .writes("username")
+                .then(|| account::normalize_username(&request.username)),
+            password,
+            request
+                .writes("email")
+                .then(|| account::normalize_email(&request.email)),
+            admin_id,
+            request.expected_version
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(DbError::from)?;
+
+        let Some(row) = row else {
+            drop(tx);
+            return Err(account::admin_version_mismatch(self.db_pool.as_ref(), admin_id).await);
+        };
 
-        println!("Admin Account: {:?}", res);
+        if request.writes("password") {
+            session::revoke_others(&mut *tx, Principal::Admin(admin_id), Some(session_id)).await?;
+        }
+
+        tx.commit().await.map_err(DbError::from)?;
+
+        let res: proto::AdminAccount = row.into();
+
+        tracing::debug!(admin_account = ?res);
 
         let response = proto::UpdateAdminAccountResponse {
             account: Some(GetAdminAccountResponse {
@@ -507,6 +1062,8 @@ impl Admin for AdminService {
                 username: res.username.to_owned(),
                 email: res.email.to_owned(),
                 created_at: res.created_at,
+                updated_at: res.updated_at,
+                version: res.version,
             }),
         };
 
@@ -517,23 +1074,34 @@ impl Admin for AdminService {
         &self,
         request: tonic::Request<proto::DeleteAdminAccountRequest>,
     ) -> Result<tonic::Response<proto::DeleteAdminAccountResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
         let request = request.get_ref();
 
-        let res = query_as!(
-            proto::AdminAccount,
-            "DELETE FROM admins WHERE admin_id = $1 RETURNING *;",
-            request.admin_id
+        let mut tx = self.db_pool.begin().await.map_err(DbError::from)?;
+
+        Self::ensure_other_owner(&mut tx, request.admin_id).await?;
+
+        let row = query_as!(
+            AdminRow,
+            "DELETE FROM admins WHERE admin_id = $1 AND ($2 = 0 OR version = $2) RETURNING *;",
+            request.admin_id,
+            request.expected_version
         )
-        .fetch_one(self.db_pool.as_ref())
+        .fetch_optional(&mut *tx)
         .await
-        .map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        .map_err(DbError::from)?;
+
+        tx.commit().await.map_err(DbError::from)?;
+
+        let Some(row) = row else {
+            return Err(
+                account::admin_version_mismatch(self.db_pool.as_ref(), request.admin_id).await,
+            );
+        };
+        let res: proto::AdminAccount = row.into();
 
-        println!("Admin Account: {:?}", res);
+        tracing::debug!(admin_account = ?res);
 
         let response = proto::DeleteAdminAccountResponse {
             account: Some(GetAdminAccountResponse {
@@ -541,44 +1109,318 @@ impl Admin for AdminService {
                 username: res.username.to_owned(),
                 email: res.email.to_owned(),
                 created_at: res.created_at,
+                updated_at: res.updated_at,
+                version: res.version,
             }),
         };
 
         Ok(tonic::Response::new(response))
     }
 
-    // User Accounts
+    // Admin Roles
 
-    async fn get_user_accounts(
+    async fn get_admin_roles(
         &self,
-        request: tonic::Request<proto::Empty>,
-    ) -> Result<tonic::Response<proto::GetUserAccountsResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
-
-        let res = query_as!(proto::UserAccount, "SELECT * FROM users;")
-            .fetch_all(self.db_pool.as_ref())
-            .await
-            .map_err(|e| {
-                println!("ERROR: {:?}", e);
-                tonic::Status::internal("Internal Server Error")
-            })?;
+        request: tonic::Request<proto::GetAdminAccountRequest>,
+    ) -> Result<tonic::Response<proto::GetAdminRolesResponse>, tonic::Status> {
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
-        res.iter().for_each(|user| {
-            println!("User Account: {:?}", user);
-        });
+        let admin_id = request.get_ref().admin_id;
 
-        let response = proto::GetUserAccountsResponse {
-            accounts: res
-                .iter()
-                .map(|user| GetUserAccountResponse {
-                    user_id: user.user_id,
-                    username: user.username.to_owned(),
-                    email: user.email.to_owned(),
-                    created_at: user.created_at,
-                    products: vec![],
-                    orders: vec![],
-                })
-                .collect(),
+        let response = proto::GetAdminRolesResponse {
+            admin_id,
+            roles: self.admin_roles(admin_id).await?,
+        };
+
+        Ok(tonic::Response::new(response))
+    }
+
+    async fn assign_admin_role(
+        &self,
+        request: tonic::Request<proto::AssignAdminRoleRequest>,
+    ) -> Result<tonic::Response<proto::GetAdminRolesResponse>, tonic::Status> {
+        tracing::debug!(request = ?request.get_ref(), "Received request");
+
+        let request = request.get_ref();
+
+        let res = query!(
+            "INSERT INTO admin_roles (admin_id, role) SELECT $1, role FROM roles WHERE role = $2 ON CONFLICT DO NOTHING RETURNING role;",
+            request.admin_id,
+            request.role
+        )
+        .fetch_optional(self.db_pool.as_ref())
+        .await
+        .map_err(DbError::from)?;
+
+        let roles = self.admin_roles(request.admin_id).await?;
+
+        if res.is_none() && !roles.contains(&request.role) {
+            return Err(tonic::Status::invalid_argument(format!(
+                "Unknown role {}",
+                request.role
+            )));
+        }
+
+        tracing::debug!(admin_roles = ?roles);
+
+        let response = proto::GetAdminRolesResponse {
+            admin_id: request.admin_id,
+            roles,
+        };
+
+        Ok(tonic::Response::new(response))
+    }
+
+    async fn revoke_admin_role(
+        &self,
+        request: tonic::Request<proto::RevokeAdminRoleRequest>,
+    ) -> Result<tonic::Response<proto::GetAdminRolesResponse>, tonic::Status> {
+        tracing::debug!(request = ?request.get_ref(), "Received request");
+
+        let request = request.get_ref();
+
+        let mut tx = self.db_pool.begin().await.map_err(DbError::from)?;
+
+        if request.role == rbac::OWNER_ROLE {
+            Self::ensure_other_owner(&mut tx, request.admin_id).await?;
+        }
+
+        query!(
+            "DELETE FROM admin_roles WHERE admin_id = $1 AND role = $2;",
+            request.admin_id,
+            request.role
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(DbError::from)?;
+
+        tx.commit().await.map_err(DbError::from)?;
+
+        let roles = self.admin_roles(request.admin_id).await?;
+
+        tracing::debug!(admin_roles = ?roles);
+
+        let response = proto::GetAdminRolesResponse {
+            admin_id: request.admin_id,
+            roles,
+        };
+
+        Ok(tonic::Response::new(response))
+    }
+
+    // Sessions
+
+    async fn list_sessions(
+        &self,
+        request: tonic::Request<proto::Empty>,
+    ) -> Result<tonic::Response<proto::ListSessionsResponse>, tonic::Status> {
+        tracing::debug!(request = ?request.get_ref(), "Received request");
+
+        let principal = Principal::Admin(auth::admin_id(&request)?);
+        let current_session_id = auth::session_id(&request)?;
+
+        let res = session::list(self.db_pool.as_ref(), principal, current_session_id).await?;
+
+        res.iter().for_each(|session| {
+            tracing::debug!(session = ?session);
+        });
+
+        let response = proto::ListSessionsResponse { sessions: res };
+
+        Ok(tonic::Response::new(response))
+    }
+
+    async fn revoke_session(
+        &self,
+        request: tonic::Request<proto::RevokeSessionRequest>,
+    ) -> Result<tonic::Response<proto::RevokeSessionResponse>, tonic::Status> {
+        tracing::debug!(request = ?request.get_ref(), "Received request");
+
+        let principal = Principal::Admin(auth::admin_id(&request)?);
+        let current_session_id = auth::session_id(&request)?;
+
+        let res = session::revoke(
+            self.db_pool.as_ref(),
+            principal,
+            request.get_ref().session_id,
+            current_session_id,
+        )
+        .await?;
+
+        tracing::debug!(session = ?res);
+
+        let response = proto::RevokeSessionResponse { session: Some(res) };
+
+        Ok(tonic::Response::new(response))
+    }
+
+    // Two-Factor Authentication
+
+    async fn enroll_totp(
+        &self,
+        request: tonic::Request<proto::Empty>,
+    ) -> Result<tonic::Response<proto::EnrollTotpResponse>, tonic::Status> {
+        tracing::debug!(request = ?request.get_ref(), "Received request");
+
+        let admin_id = auth::admin_id(&request)?;
+
+        if totp::is_enabled(self.db_pool.as_ref(), admin_id).await? {
+            return Err(tonic::Status::failed_precondition(
+                "TOTP is already enabled",
+            ));
+        }
+
+        let secret = totp::generate_secret();
+
+        let username = query_scalar!(
+            "INSERT INTO admin_totp (admin_id, secret, enabled) VALUES ($1, $2, FALSE) ON CONFLICT (admin_id) DO UPDATE SET secret = EXCLUDED.secret, last_used_step = NULL, created_at = now() WHERE NOT admin_totp.enabled RETURNING (SELECT username FROM admins WHERE admin_id = $1);",
+            admin_id,
+            secret
+        )
+        .fetch_optional(self.db_pool.as_ref())
+        .await
+        .map_err(DbError::from)?
+        .flatten()
+        .ok_or_else(|| tonic::Status::failed_precondition("TOTP is already enabled"))?;
+
+        let response = proto::EnrollTotpResponse {
+            secret: totp::encode_secret(&secret),
+            otpauth_uri: totp::otpauth_uri(&username, &secret),
+        };
+
+        Ok(tonic::Response::new(response))
+    }
+
+    async fn confirm_totp(
+        &self,
+        request: tonic::Request<proto::ConfirmTotpRequest>,
+    ) -> Result<tonic::Response<proto::ConfirmTotpResponse>, tonic::Status> {
+        tracing::debug!(request = ?request.get_ref(), "Received request");
+
+        let admin_id = auth::admin_id(&request)?;
+
+        if !totp::check_totp(
+            self.db_pool.as_ref(),
+            admin_id,
+            &request.get_ref().code,
+            true,
+        )
+        .await?
+        {
+            return Err(tonic::Status::invalid_argument("Invalid TOTP code"));
+        }
+
+        let recovery_codes = totp::generate_recovery_codes();
+
+        let mut tx = self.db_pool.begin().await.map_err(DbError::from)?;
+
+        let res = query!(
+            "UPDATE admin_totp SET enabled = TRUE WHERE admin_id = $1 AND NOT enabled;",
+            admin_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(DbError::from)?;
+
+        if res.rows_affected() == 0 {
+            return Err(tonic::Status::failed_precondition(
+                "TOTP is already enabled",
+            ));
+        }
+
+        query!(
+            "DELETE FROM admin_recovery_codes WHERE admin_id = $1;",
+            admin_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(DbError::from)?;
+
+        for (_, code_hash) in &recovery_codes {
+            query!(
+                "INSERT INTO admin_recovery_codes (admin_id, code_hash) VALUES ($1, $2);",
+                admin_id,
+                code_hash
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(DbError::from)?;
+        }
+
+        tx.commit().await.map_err(DbError::from)?;
+
+        let response = proto::ConfirmTotpResponse {
+            recovery_codes: recovery_codes.into_iter().map(|(code, _)| code).collect(),
+        };
+
+        Ok(tonic::Response::new(response))
+    }
+
+    async fn disable_totp(
+        &self,
+        request: tonic::Request<proto::DisableTotpRequest>,
+    ) -> Result<tonic::Response<proto::Empty>, tonic::Status> {
+        tracing::debug!(request = ?request.get_ref(), "Received request");
+
+        let admin_id = auth::admin_id(&request)?;
+
+        if totp::is_required(self.db_pool.as_ref(), admin_id).await? {
+            return Err(tonic::Status::failed_precondition(
+                "TOTP is required by one of your roles",
+            ));
+        }
+
+        if !totp::check(self.db_pool.as_ref(), admin_id, &request.get_ref().code).await? {
+            return Err(tonic::Status::invalid_argument("Invalid TOTP code"));
+        }
+
+        query!(
+            "WITH codes AS (DELETE FROM admin_recovery_codes WHERE admin_id = $1) DELETE FROM admin_totp WHERE admin_id = $1;",
+            admin_id
+        )
+        .execute(self.db_pool.as_ref())
+        .await
+        .map_err(DbError::from)?;
+
+        Ok(tonic::Response::new(proto::Empty {}))
+    }
+
+    // User Accounts
+
+    async fn get_user_accounts(
+        &self,
+        request: tonic::Request<proto::GetUserAccountsRequest>,
+    ) -> Result<tonic::Response<proto::GetUserAccountsResponse>, tonic::Status> {
+        tracing::debug!(request = ?request.get_ref(), "Received request");
+
+        let page: Page<UserRow> = page::fetch(
+            self.db_pool.as_ref(),
+            &account::USER_LISTING,
+            request.get_ref(),
+            vec![],
+        )
+        .await?;
+        let res: Vec<proto::UserAccount> = page.items.into_iter().map(Into::into).collect();
+
+        res.iter().for_each(|user| {
+            tracing::debug!(user_account = ?user);
+        });
+
+        let response = proto::GetUserAccountsResponse {
+            accounts: res
+                .iter()
+                .map(|user| GetUserAccountResponse {
+                    user_id: user.user_id,
+                    username: user.username.to_owned(),
+                    email: user.email.to_owned(),
+                    email_verified: user.email_verified,
+                    created_at: user.created_at.clone(),
+                    updated_at: user.updated_at.clone(),
+                    version: user.version,
+                    orders: vec![],
+                })
+                .collect(),
+            next_page_token: page.next_page_token,
         };
 
         Ok(tonic::Response::new(response))
@@ -587,28 +1429,28 @@ impl Admin for AdminService {
         &self,
         request: tonic::Request<proto::GetUserAccountRequest>,
     ) -> Result<tonic::Response<proto::GetUserAccountResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
-        let res = query_as!(
-            proto::UserAccount,
+        let res: proto::UserAccount = query_as!(
+            UserRow,
             "SELECT * FROM users WHERE user_id = $1;",
             request.get_ref().user_id
         )
         .fetch_one(self.db_pool.as_ref())
         .await
-        .map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        .map_err(DbError::from)?
+        .into();
 
-        println!("User Account: {:?}", res);
+        tracing::debug!(user_account = ?res);
 
         let response = proto::GetUserAccountResponse {
             user_id: res.user_id,
             username: res.username.to_owned(),
             email: res.email.to_owned(),
+            email_verified: res.email_verified,
             created_at: res.created_at,
-            products: vec![],
+            updated_at: res.updated_at,
+            version: res.version,
             orders: vec![],
         };
 
@@ -619,36 +1461,36 @@ impl Admin for AdminService {
         &self,
         request: tonic::Request<proto::CreateUserAccountRequest>,
     ) -> Result<tonic::Response<proto::CreateUserAccountResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
         let request = request.get_ref();
 
-        let res = query_as!(
-            proto::UserAccount,
-            "INSERT INTO users (username, password, email, products, orders, created_at) VALUES ($1, $2, $3, $4, $5, $6) RETURNING *;",
-            request.username,
-            request.password,
-            request.email,
-            &vec![],
-            &vec![],
-            time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap().as_secs() as f64
+        let res: proto::UserAccount = query_as!(
+            UserRow,
+            r#"INSERT INTO users (username, password, email, orders)
+                VALUES ($1, $2, $3, $4)
+                RETURNING *;"#,
+            account::normalize_username(&request.username),
+            password::hash(&request.password).await?,
+            account::normalize_email(&request.email),
+            &vec![]
         )
         .fetch_one(self.db_pool.as_ref())
         .await
-        .map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        .map_err(DbError::from)?
+        .into();
 
-        println!("User Account: {:?}", res);
+        tracing::debug!(user_account = ?res);
 
         let response = proto::CreateUserAccountResponse {
             account: Some(GetUserAccountResponse {
                 user_id: res.user_id,
                 username: res.username.to_owned(),
                 email: res.email.to_owned(),
+                email_verified: res.email_verified,
                 created_at: res.created_at,
-                products: vec![],
+                updated_at: res.updated_at,
+                version: res.version,
                 orders: vec![],
             }),
         };
@@ -660,31 +1502,38 @@ impl Admin for AdminService {
         &self,
         request: tonic::Request<proto::DeleteUserAccountRequest>,
     ) -> Result<tonic::Response<proto::DeleteUserAccountResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
         let request = request.get_ref();
 
-        let res = query_as!(
-            proto::UserAccount,
-            "DELETE FROM users WHERE user_id = $1 RETURNING *;",
-            request.user_id
+        let row = query_as!(
+            UserRow,
+            "DELETE FROM users WHERE user_id = $1 AND ($2 = 0 OR version = $2) RETURNING *;",
+            request.user_id,
+            request.expected_version
         )
-        .fetch_one(self.db_pool.as_ref())
+        .fetch_optional(self.db_pool.as_ref())
         .await
-        .map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        .map_err(DbError::from)?;
+
+        let Some(row) = row else {
+            return Err(
+                account::user_version_mismatch(self.db_pool.as_ref(), request.user_id).await,
+            );
+        };
+        let res: proto::UserAccount = row.into();
 
-        println!("User Account: {:?}", res);
+        tracing::debug!(user_account = ?res);
 
         let response = proto::DeleteUserAccountResponse {
             account: Some(GetUserAccountResponse {
                 user_id: res.user_id,
                 username: res.username.to_owned(),
                 email: res.email.to_owned(),
+                email_verified: res.email_verified,
                 created_at: res.created_at,
-                products: vec![],
+                updated_at: res.updated_at,
+                version: res.version,
                 orders: vec![],
             }),
         };
@@ -694,69 +1543,65 @@ impl Admin for AdminService {
 
     async fn get_products_by_user(
         &self,
-        request: tonic::Request<proto::GetUserAccountRequest>,
+        request: tonic::Request<proto::GetUserProductsRequest>,
     ) -> Result<tonic::Response<proto::GetProductsResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
-        let res = query!(
-            "SELECT products FROM users WHERE user_id = $1;",
+        let user_id = query_scalar!(
+            "SELECT user_id FROM users WHERE user_id = $1;",
             request.get_ref().user_id
         )
-        .fetch_all(self.db_pool.as_ref())
+        .fetch_optional(self.db_pool.as_ref())
         .await
-        .map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
-
-        let products: Vec<i32> = res
-            .iter()
-            .map(|row| *row.products.get(0).unwrap())
-            .collect();
-
-        let res = query_as!(
-            proto::Product,
-            "SELECT * FROM products WHERE product_id = ANY($1);",
-            &products
+        .map_err(DbError::from)?
+        .ok_or_else(|| tonic::Status::not_found("User not found"))?;
+
+        let page: Page<ProductRow> = page::fetch(
+            self.db_pool.as_ref(),
+            &product::CART_LISTING,
+            request.get_ref(),
+            vec![Condition::eq("cart_items.user_id", Value::Int(user_id))],
         )
-        .fetch_all(self.db_pool.as_ref())
-        .await
-        .map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        .await?;
+        let res: Vec<proto::Product> = page.items.into_iter().map(Into::into).collect();
 
         res.iter().for_each(|product| {
-            println!("Product: {:?}", product);
+            tracing::debug!(product = ?product);
         });
 
-        let response = proto::GetProductsResponse { products: res };
+        let response = proto::GetProductsResponse {
+            products: res,
+            next_page_token: page.next_page_token,
+        };
 
         Ok(tonic::Response::new(response))
     }
     async fn get_orders_by_user(
         &self,
-        request: tonic::Request<proto::GetUserAccountRequest>,
+        request: tonic::Request<proto::GetUserOrdersRequest>,
     ) -> Result<tonic::Response<proto::GetOrdersResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
-
-        let res = query_as!(
-            proto::Order,
-            "SELECT * FROM orders WHERE user_id = $1;",
-            request.get_ref().user_id
+        tracing::debug!(request = ?request.get_ref(), "Received request");
+
+        let page: Page<OrderRow> = page::fetch(
+            self.db_pool.as_ref(),
+            &order::LISTING,
+            request.get_ref(),
+            vec![Condition::eq(
+                "user_id",
+                Value::Int(request.get_ref().user_id),
+            )],
         )
-        .fetch_all(self.db_pool.as_ref())
-        .await
-        .map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        .await?;
+        let res = order::with_items(self.db_pool.as_ref(), page.items).await?;
 
         res.iter().for_each(|order| {
-            println!("Order: {:?}", order);
+            tracing::debug!(order = ?order);
         });
 
-        let response = proto::GetOrdersResponse { orders: res };
+        let response = proto::GetOrdersResponse {
+            orders: res,
+            next_page_token: page.next_page_token,
+        };
 
         Ok(tonic::Response::new(response))
     }
@@ -768,24 +1613,27 @@ impl User for UserService {
         &self,
         request: tonic::Request<proto::Empty>,
     ) -> Result<tonic::Response<proto::GetUserAccountResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
-        let res = query_as!(proto::UserAccount, "SELECT * FROM users;")
-            .fetch_one(self.db_pool.as_ref())
-            .await
-            .map_err(|e| {
-                println!("ERROR: {:?}", e);
-                tonic::Status::internal("Internal Server Error")
-            })?;
+        let user_id = auth::user_id(&request)?;
+
+        let res: proto::UserAccount =
+            query_as!(UserRow, "SELECT * FROM users WHERE user_id = $1;", user_id)
+                .fetch_one(self.db_pool.as_ref())
+                .await
+                .map_err(DbError::from)?
+                .into();
 
-        println!("User Account: {:?}", res);
+        tracing::debug!(user_account = ?res);
 
         let response = proto::GetUserAccountResponse {
             user_id: res.user_id,
             username: res.username,
             email: res.email,
+            email_verified: res.email_verified,
             created_at: res.created_at,
-            products: res.products,
+            updated_at: res.updated_at,
+            version: res.version,
             orders: res.orders,
         };
 
@@ -796,40 +1644,78 @@ impl User for UserService {
         &self,
         request: tonic::Request<proto::UpdateUserAccountRequest>,
     ) -> Result<tonic::Response<proto::UpdateUserAccountResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
-        let headers = request.metadata();
+        let user_id = auth::owned_user_id(&request, request.get_ref().user_id)?;
+        let session_id = auth::session_id(&request)?;
         let request = request.get_ref();
 
-        let user_id = headers
-            .get("user_id")
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .parse::<i32>()
-            .unwrap();
+        let password = match request.writes("password") {
+            true => Some(password::hash(&request.password).await?),
+            false => None,
+        };
 
-        let res = query_as!(
-            proto::UserAccount,
-            "UPDATE users SET username = $1, password = $2, email = $3 WHERE user_id = $4 RETURNING *;",
-            request.username,
-            request.password,
-            request.email,
-            user_id
-        ).fetch_one(self.db_pool.as_ref()).await.map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        let mut tx = self.db_pool.begin().await.map_err(DbError::from)?;
+
+        let row = query_as!(
+            UserRow,
+            r#"UPDATE users SET
+                    username = COALESCE($1, username),
+                    password = COALESCE($2, password),
+                    email = COALESCE($3, email),
+                    email_verified = (email_verified AND email = COALESCE($3, email))
+                WHERE user_id = $4 AND ($5 = 0 OR version = $5)
+                RETURNING *;"#,
+            request
+                .writes("username")
+                .then(|| account::normalize_username(&request.username)),
+            password,
+            request
+                .writes("email")
+                .then(|| account::normalize_email(&request.email)),
+            user_id,
+            request.expected_version
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(DbError::from)?;
+
+        let Some(row) = row else {
+            drop(tx);
+            return Err(account::user_version_mismatch(self.db_pool.as_ref(), user_id).await);
+        };
+
+        if request.writes("password") {
+            session::revoke_others(&mut *tx, Principal::User(user_id), Some(session_id)).await?;
+        }
 
-        println!("User Account: {:?}", res);
+        tx.commit().await.map_err(DbError::from)?;
+
+        let res: proto::UserAccount = row.into();
+
+        tracing::debug!(user_account = ?res);
+
+        // Also serves as the way to get a fresh code for an unverified address.
+        if !res.email_verified {
+            account::send_token(
+                self.db_pool.as_ref(),
+                self.mailer.as_ref(),
+                Purpose::EmailVerification,
+                res.user_id,
+                &res.email,
+            )
+            .await?;
+        }
 
         let response = proto::UpdateUserAccountResponse {
             account: Some(GetUserAccountResponse {
                 user_id: res.user_id,
                 username: res.username,
                 email: res.email,
+                email_verified: res.email_verified,
                 created_at: res.created_at,
-                products: res.products,
+                updated_at: res.updated_at,
+                version: res.version,
                 orders: res.orders,
             }),
         };
@@ -841,31 +1727,36 @@ impl User for UserService {
         &self,
         request: tonic::Request<proto::DeleteUserAccountRequest>,
     ) -> Result<tonic::Response<proto::DeleteUserAccountResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
-        let request = request.get_ref();
+        let user_id = auth::owned_user_id(&request, request.get_ref().user_id)?;
 
-        let res = query_as!(
-            proto::UserAccount,
-            "DELETE FROM users WHERE user_id = $1 RETURNING *;",
-            request.user_id
+        let row = query_as!(
+            UserRow,
+            "DELETE FROM users WHERE user_id = $1 AND ($2 = 0 OR version = $2) RETURNING *;",
+            user_id,
+            request.get_ref().expected_version
         )
-        .fetch_one(self.db_pool.as_ref())
+        .fetch_optional(self.db_pool.as_ref())
         .await
-        .map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        .map_err(DbError::from)?;
+
+        let Some(row) = row else {
+            return Err(account::user_version_mismatch(self.db_pool.as_ref(), user_id).await);
+        };
+        let res: proto::UserAccount = row.into();
 
-        println!("User Account: {:?}", res);
+        tracing::debug!(user_account = ?res);
 
         let response = proto::DeleteUserAccountResponse {
             account: Some(GetUserAccountResponse {
                 user_id: res.user_id,
                 username: res.username,
                 email: res.email,
+                email_verified: res.email_verified,
                 created_at: res.created_at,
-                products: res.products,
+                updated_at: res.updated_at,
+                version: res.version,
                 orders: res.orders,
             }),
         };
@@ -879,39 +1770,41 @@ impl User for UserService {
         &self,
         request: tonic::Request<proto::AddToCartRequest>,
     ) -> Result<tonic::Response<proto::GetProductResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
+        let user_id = auth::owned_user_id(&request, request.get_ref().user_id)?;
         let request = request.get_ref();
 
         let find_product = query_as!(
-            proto::Product,
+            ProductRow,
             "SELECT * FROM products WHERE product_id = $1;",
             request.product_id
         )
         .fetch_one(self.db_pool.as_ref())
         .await
-        .map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
-
-        let res = query_as!(
-            proto::UserAccount,
-            "UPDATE users SET products = array_append(products, $1) WHERE user_id = $2 RETURNING *",
+        .map_err(DbError::from)?;
+
+        cart::check_currency(self.db_pool.as_ref(), user_id, &find_product.currency_code).await?;
+
+        let quantity = query_scalar!(
+            "INSERT INTO cart_items (user_id, product_id, quantity) VALUES ($1, $2, $3) ON CONFLICT (user_id, product_id) DO UPDATE SET quantity = cart_items.quantity + EXCLUDED.quantity RETURNING quantity;",
+            user_id,
             request.product_id,
-            request.user_id
+            request.quantity.max(1)
         )
         .fetch_one(self.db_pool.as_ref())
         .await
-        .map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        .map_err(DbError::from)?;
 
-        println!("User Account: {:?}", res);
+        tracing::debug!(
+            user_id,
+            product_id = request.product_id,
+            quantity,
+            "Cart updated"
+        );
 
         let response = proto::GetProductResponse {
-            product: Some(find_product),
+            product: Some(find_product.into()),
         };
 
         Ok(tonic::Response::new(response))
@@ -921,36 +1814,53 @@ impl User for UserService {
         &self,
         request: tonic::Request<proto::RemoveFromCartRequest>,
     ) -> Result<tonic::Response<proto::GetProductResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
+        let user_id = auth::owned_user_id(&request, request.get_ref().user_id)?;
         let request = request.get_ref();
 
-        let res = query_as!(
-            proto::UserAccount,
-            "UPDATE users SET products = array_remove(products, $1) WHERE user_id = $2 RETURNING *",
+        let mut tx = self.db_pool.begin().await.map_err(DbError::from)?;
+
+        // A quantity of 0 (or at least what's in the cart) removes the line.
+        let quantity = query_scalar!(
+            "UPDATE cart_items SET quantity = quantity - $3 WHERE user_id = $1 AND product_id = $2 AND quantity > $3 AND $3 > 0 RETURNING quantity;",
+            user_id,
             request.product_id,
-            request.user_id
+            request.quantity
         )
-        .fetch_one(self.db_pool.as_ref())
+        .fetch_optional(&mut *tx)
         .await
-        .map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        .map_err(DbError::from)?;
+
+        if quantity.is_none() {
+            query!(
+                "DELETE FROM cart_items WHERE user_id = $1 AND product_id = $2;",
+                user_id,
+                request.product_id
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(DbError::from)?;
+        }
 
-        let find_product = query_as!(
-            proto::Product,
+        tx.commit().await.map_err(DbError::from)?;
+
+        let find_product: proto::Product = query_as!(
+            ProductRow,
             "SELECT * FROM products WHERE product_id = $1;",
             request.product_id
         )
         .fetch_one(self.db_pool.as_ref())
         .await
-        .map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        .map_err(DbError::from)?
+        .into();
 
-        println!("User Account: {:?}", res);
+        tracing::debug!(
+            user_id,
+            product_id = request.product_id,
+            quantity = quantity.unwrap_or(0),
+            "Cart updated"
+        );
 
         let response = proto::GetProductResponse {
             product: Some(find_product),
@@ -959,62 +1869,134 @@ impl User for UserService {
         Ok(tonic::Response::new(response))
     }
 
+    async fn get_cart(
+        &self,
+        request: tonic::Request<proto::Empty>,
+    ) -> Result<tonic::Response<proto::Cart>, tonic::Status> {
+        tracing::debug!(request = ?request.get_ref(), "Received request");
+
+        let user_id = auth::user_id(&request)?;
+
+        let lines = cart::items(self.db_pool.as_ref(), user_id).await?;
+
+        let response = proto::Cart {
+            total: cart::total(&lines)
+                .map(|(total, currency_code)| money::to_proto(total, currency_code)),
+            items: lines.iter().map(cart::Line::to_proto).collect(),
+        };
+
+        Ok(tonic::Response::new(response))
+    }
+
     async fn checkout(
         &self,
         request: tonic::Request<proto::CheckoutRequest>,
     ) -> Result<tonic::Response<proto::CheckoutResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
-        let headers = request.metadata();
-        // let request = request.get_ref();
+        let user_id = auth::user_id(&request)?;
 
-        let user_id = headers
-            .get("user_id")
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .parse::<i32>()
-            .unwrap();
+        let mut tx = self.db_pool.begin().await.map_err(DbError::from)?;
 
-        let res = query_as!(
-            proto::UserAccount,
-            "SELECT * FROM users WHERE user_id = $1;",
-            user_id
+        // Conflicting checkouts fail with ABORTED and can simply be retried.
+        query!("SET TRANSACTION ISOLATION LEVEL SERIALIZABLE;")
+            .execute(&mut *tx)
+            .await
+            .map_err(DbError::from)?;
+
+        let lines = cart::lock(&mut tx, user_id).await?;
+
+        if lines.is_empty() {
+            return Err(tonic::Status::failed_precondition("Cart is empty"));
+        }
+
+        // A product's currency may have changed since it was added.
+        let Some((total, currency_code)) = cart::total(&lines) else {
+            let mut currency_codes: Vec<&str> = lines
+                .iter()
+                .map(|line| line.currency_code.as_str())
+                .collect();
+            currency_codes.sort_unstable();
+            currency_codes.dedup();
+
+            return Err(cart::mixed_currencies(&currency_codes));
+        };
+
+        if !money::fits(total) {
+            return Err(tonic::Status::failed_precondition(
+                "Cart total is too large for one order",
+            ));
+        }
+
+        let row = query_as!(
+            OrderRow,
+            r#"INSERT INTO orders (user_id, total, currency_code, status)
+                VALUES ($1, $2, $3, $4)
+                RETURNING *;"#,
+            user_id,
+            total,
+            currency_code,
+            order::status_name(OrderStatus::Pending)
         )
-        .fetch_one(self.db_pool.as_ref())
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(DbError::from)?;
+
+        // Prices and names are copied so later product edits don't change the order.
+        query!(
+            "INSERT INTO order_items (order_id, product_id, quantity, unit_price, name_snapshot) SELECT $1, * FROM UNNEST($2::INT[], $3::INT[], $4::NUMERIC[], $5::TEXT[]);",
+            row.order_id,
+            &lines.iter().map(|line| line.product_id).collect::<Vec<_>>(),
+            &lines.iter().map(|line| line.quantity).collect::<Vec<_>>(),
+            &lines.iter().map(|line| line.unit_price).collect::<Vec<_>>(),
+            &lines.iter().map(|line| line.name.clone()).collect::<Vec<_>>()
+        )
+        .execute(&mut *tx)
         .await
-        .map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        .map_err(DbError::from)?;
 
-        let products: Vec<i32> = res.products.iter().map(|product| *product).collect();
+        let items: Vec<proto::LineItem> = lines.iter().map(cart::Line::to_proto).collect();
 
-        let find_products = query_as!(
-            proto::Product,
-            "SELECT * FROM products WHERE product_id = ANY($1);",
-            &products
+        inventory::reserve(&mut tx, row.order_id, &items).await?;
+
+        query!("DELETE FROM cart_items WHERE user_id = $1;", user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(DbError::from)?;
+
+        query!(
+            "UPDATE users SET orders = array_append(orders, $1) WHERE user_id = $2;",
+            row.order_id,
+            user_id
         )
-        .fetch_all(self.db_pool.as_ref())
+        .execute(&mut *tx)
         .await
-        .map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        .map_err(DbError::from)?;
+
+        order::record_status(
+            &mut *tx,
+            row.order_id,
+            None,
+            OrderStatus::Pending,
+            Principal::User(user_id),
+            "",
+        )
+        .await?;
 
-        let total: f64 = find_products.iter().map(|product| product.price).sum();
+        tx.commit().await.map_err(DbError::from)?;
 
-        let order = query_as!(
-            proto::Order,
-            "INSERT INTO orders (user_id, products, total, status) VALUES ($1, $2, $3, $4) RETURNING *;",
-            user_id,
-            &products,
-            total,
-            "Pending"
-        ).fetch_one(self.db_pool.as_ref()).await.map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        tracing::info!(order_id = row.order_id, user_id, "Order placed");
+
+        let order = proto::Order {
+            order_id: row.order_id,
+            user_id: row.user_id,
+            total: Some(money::to_proto(row.total, &row.currency_code)),
+            status: OrderStatus::Pending as i32,
+            created_at: Some(timestamp::to_proto(row.created_at)),
+            updated_at: Some(timestamp::to_proto(row.updated_at)),
+            version: row.version,
+            items,
+        };
 
         let response = proto::CheckoutResponse { order: Some(order) };
 
@@ -1023,75 +2005,270 @@ impl User for UserService {
 
     async fn get_products(
         &self,
-        request: tonic::Request<proto::GetUserAccountRequest>,
+        request: tonic::Request<proto::GetUserProductsRequest>,
     ) -> Result<tonic::Response<proto::GetProductsResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
-        let request = request.get_ref();
+        let user_id = auth::owned_user_id(&request, request.get_ref().user_id)?;
 
-        let res = query!(
-            "SELECT products FROM users WHERE user_id = $1;",
-            request.user_id
-        )
-        .fetch_all(self.db_pool.as_ref())
-        .await
-        .map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
-
-        let products: Vec<i32> = res
-            .iter()
-            .map(|row| *row.products.get(0).unwrap())
-            .collect();
-
-        let res = query_as!(
-            proto::Product,
-            "SELECT * FROM products WHERE product_id = ANY($1);",
-            &products
+        query_scalar!("SELECT user_id FROM users WHERE user_id = $1;", user_id)
+            .fetch_optional(self.db_pool.as_ref())
+            .await
+            .map_err(DbError::from)?
+            .ok_or_else(|| tonic::Status::not_found("User not found"))?;
+
+        let page: Page<ProductRow> = page::fetch(
+            self.db_pool.as_ref(),
+            &product::CART_LISTING,
+            request.get_ref(),
+            vec![Condition::eq("cart_items.user_id", Value::Int(user_id))],
         )
-        .fetch_all(self.db_pool.as_ref())
-        .await
-        .map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        .await?;
+        let res: Vec<proto::Product> = page.items.into_iter().map(Into::into).collect();
 
         res.iter().for_each(|product| {
-            println!("Product: {:?}", product);
+            tracing::debug!(product = ?product);
         });
 
-        let response = proto::GetProductsResponse { products: res };
+        let response = proto::GetProductsResponse {
+            products: res,
+            next_page_token: page.next_page_token,
+        };
 
         Ok(tonic::Response::new(response))
     }
 
     async fn get_orders(
         &self,
-        request: tonic::Request<proto::GetUserAccountRequest>,
+        request: tonic::Request<proto::GetUserOrdersRequest>,
     ) -> Result<tonic::Response<proto::GetOrdersResponse>, tonic::Status> {
-        println!("\nREQUEST: {:?}", request);
+        tracing::debug!(request = ?request.get_ref(), "Received request");
 
-        let request = request.get_ref();
+        let user_id = auth::owned_user_id(&request, request.get_ref().user_id)?;
 
-        let res = query_as!(
-            proto::Order,
-            "SELECT * FROM orders WHERE user_id = $1;",
-            request.user_id
+        let page: Page<OrderRow> = page::fetch(
+            self.db_pool.as_ref(),
+            &order::LISTING,
+            request.get_ref(),
+            vec![Condition::eq("user_id", Value::Int(user_id))],
         )
-        .fetch_all(self.db_pool.as_ref())
-        .await
-        .map_err(|e| {
-            println!("ERROR: {:?}", e);
-            tonic::Status::internal("Internal Server Error")
-        })?;
+        .await?;
+        let res = order::with_items(self.db_pool.as_ref(), page.items).await?;
 
         res.iter().for_each(|order| {
-            println!("Order: {:?}", order);
+            tracing::debug!(order = ?order);
+        });
+
+        let response = proto::GetOrdersResponse {
+            orders: res,
+            next_page_token: page.next_page_token,
+        };
+
+        Ok(tonic::Response::new(response))
+    }
+
+    // Sessions
+
+    async fn list_sessions(
+        &self,
+        request: tonic::Request<proto::Empty>,
+    ) -> Result<tonic::Response<proto::ListSessionsResponse>, tonic::Status> {
+        tracing::debug!(request = ?request.get_ref(), "Received request");
+
+        let principal = Principal::User(auth::user_id(&request)?);
+        let current_session_id = auth::session_id(&request)?;
+
+        let res = session::list(self.db_pool.as_ref(), principal, current_session_id).await?;
+
+        res.iter().for_each(|session| {
+            tracing::debug!(session = ?session);
         });
 
-        let response = proto::GetOrdersResponse { orders: res };
+        let response = proto::ListSessionsResponse { sessions: res };
+
+        Ok(tonic::Response::new(response))
+    }
+
+    async fn revoke_session(
+        &self,
+        request: tonic::Request<proto::RevokeSessionRequest>,
+    ) -> Result<tonic::Response<proto::RevokeSessionResponse>, tonic::Status> {
+        tracing::debug!(request = ?request.get_ref(), "Received request");
+
+        let principal = Principal::User(auth::user_id(&request)?);
+        let current_session_id = auth::session_id(&request)?;
+
+        let res = session::revoke(
+            self.db_pool.as_ref(),
+            principal,
+            request.get_ref().session_id,
+            current_session_id,
+        )
+        .await?;
+
+        tracing::debug!(session = ?res);
+
+        let response = proto::RevokeSessionResponse { session: Some(res) };
 
         Ok(tonic::Response::new(response))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use crate::mailer::StdoutMailer;
+
+    fn storefront(db_pool: &sqlx::PgPool) -> StorefrontService {
+        StorefrontService::new(Arc::new(db_pool.clone()), Arc::new(StdoutMailer))
+    }
+
+    fn login_request(username: &str, password: &str) -> tonic::Request<proto::LoginRequest> {
+        tonic::Request::new(proto::LoginRequest {
+            username: username.to_owned(),
+            password: password.to_owned(),
+        })
+    }
+
+    #[sqlx::test]
+    async fn login_upgrades_plaintext_passwords(db_pool: sqlx::PgPool) {
+        let user_id = fixtures::user(&db_pool, "user").await;
+        query!(
+            "UPDATE users SET password = $1 WHERE user_id = $2;",
+            fixtures::PASSWORD,
+            user_id
+        )
+        .execute(&db_pool)
+        .await
+        .unwrap();
+
+        storefront(&db_pool)
+            .login(login_request("user", fixtures::PASSWORD))
+            .await
+            .unwrap();
+
+        let stored = query_scalar!("SELECT password FROM users WHERE user_id = $1;", user_id)
+            .fetch_one(&db_pool)
+            .await
+            .unwrap();
+        assert_ne!(stored, fixtures::PASSWORD);
+        assert_eq!(
+            password::verify(fixtures::PASSWORD, Some(&stored))
+                .await
+                .unwrap(),
+            Verification::Valid {
+                needs_rehash: false
+            }
+        );
+    }
+
+    #[sqlx::test]
+    async fn admin_login_upgrades_plaintext_passwords(db_pool: sqlx::PgPool) {
+        let admin_id = fixtures::admin(&db_pool, "admin").await;
+        query!(
+            "UPDATE admins SET password = $1 WHERE admin_id = $2;",
+            fixtures::PASSWORD,
+            admin_id
+        )
+        .execute(&db_pool)
+        .await
+        .unwrap();
+
+        storefront(&db_pool)
+            .admin_login(login_request("admin", fixtures::PASSWORD))
+            .await
+            .unwrap();
+
+        let stored = query_scalar!("SELECT password FROM admins WHERE admin_id = $1;", admin_id)
+            .fetch_one(&db_pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            password::verify(fixtures::PASSWORD, Some(&stored))
+                .await
+                .unwrap(),
+            Verification::Valid {
+                needs_rehash: false
+            }
+        );
+    }
+
+    #[sqlx::test]
+    async fn login_rejects_unknown_users_like_wrong_passwords(db_pool: sqlx::PgPool) {
+        fixtures::user(&db_pool, "user").await;
+
+        let wrong_password = storefront(&db_pool)
+            .login(login_request("user", "wrong password"))
+            .await
+            .unwrap_err();
+        let unknown_user = storefront(&db_pool)
+            .login(login_request("nobody", fixtures::PASSWORD))
+            .await
+            .unwrap_err();
+
+        assert_eq!(wrong_password.code(), Code::Unauthenticated);
+        assert_eq!(unknown_user.code(), wrong_password.code());
+        assert_eq!(unknown_user.message(), wrong_password.message());
+    }
+
+    #[sqlx::test]
+    async fn login_rehashes_keep_the_account_version(db_pool: sqlx::PgPool) {
+        let user_id = fixtures::user(&db_pool, "user").await;
+        let admin_id = fixtures::admin(&db_pool, "admin").await;
+        query!(
+            "UPDATE users SET password = $1 WHERE user_id = $2;",
+            fixtures::PASSWORD,
+            user_id
+        )
+        .execute(&db_pool)
+        .await
+        .unwrap();
+        query!(
+            "UPDATE admins SET password = $1 WHERE admin_id = $2;",
+            fixtures::PASSWORD,
+            admin_id
+        )
+        .execute(&db_pool)
+        .await
+        .unwrap();
+
+        let user_version = query_scalar!("SELECT version FROM users WHERE user_id = $1;", user_id)
+            .fetch_one(&db_pool)
+            .await
+            .unwrap();
+        let admin_version =
+            query_scalar!("SELECT version FROM admins WHERE admin_id = $1;", admin_id)
+                .fetch_one(&db_pool)
+                .await
+                .unwrap();
+        assert_eq!((user_version, admin_version), (2, 2));
+
+        storefront(&db_pool)
+            .login(login_request("user", fixtures::PASSWORD))
+            .await
+            .unwrap();
+        storefront(&db_pool)
+            .admin_login(login_request("admin", fixtures::PASSWORD))
+            .await
+            .unwrap();
+
+        let user = query!(
+            "SELECT version, password FROM users WHERE user_id = $1;",
+            user_id
+        )
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+        let admin = query!(
+            "SELECT version, password FROM admins WHERE admin_id = $1;",
+            admin_id
+        )
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+        assert_ne!(user.password, fixtures::PASSWORD);
+        assert_ne!(admin.password, fixtures::PASSWORD);
+        assert_eq!((user.version, admin.version), (2, 2));
+    }
+}
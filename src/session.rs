@@ -0,0 +1,292 @@
+use sqlx::types::time::OffsetDateTime;
+use sqlx::{query, query_as, query_scalar};
+
+use crate::auth::{self, Principal};
+use crate::error::DbError;
+use crate::{proto, timestamp, token, totp};
+
+pub(crate) const REFRESH_TOKEN_TTL_SECS: f64 = 60.0 * 60.0 * 24.0 * 30.0;
+
+struct SessionRow {
+    session_id: i32,
+    created_at: OffsetDateTime,
+    last_used_at: OffsetDateTime,
+    expires_at: OffsetDateTime,
+    current: bool,
+}
+
+impl From<SessionRow> for proto::Session {
+    fn from(row: SessionRow) -> Self {
+        Self {
+            session_id: row.session_id,
+            current: row.current,
+            created_at: Some(timestamp::to_proto(row.created_at)),
+            last_used_at: Some(timestamp::to_proto(row.last_used_at)),
+            expires_at: Some(timestamp::to_proto(row.expires_at)),
+        }
+    }
+}
+
+fn owner(principal: Principal) -> (Option<i32>, Option<i32>) {
+    match principal {
+        Principal::User(user_id) => (Some(user_id), None),
+        Principal::Admin(admin_id) => (None, Some(admin_id)),
+    }
+}
+
+async fn login_response(
+    db_pool: &sqlx::PgPool,
+    principal: Principal,
+    session_id: i32,
+    refresh_token: String,
+) -> Result<proto::LoginResponse, tonic::Status> {
+    let totp_enrollment_required = match principal {
+        Principal::User(_) => false,
+        Principal::Admin(admin_id) => totp::enrollment_required(db_pool, admin_id).await?,
+    };
+
+    Ok(proto::LoginResponse {
+        access_token: auth::issue_token(principal, session_id, totp_enrollment_required)?,
+        token_type: "Bearer".to_owned(),
+        expires_in: auth::ACCESS_TOKEN_TTL_SECS as i64,
+        refresh_token,
+        session_id,
+        totp_enrollment_required,
+        ..Default::default()
+    })
+}
+
+/// Opens a new session for a freshly authenticated principal.
+pub(crate) async fn start(
+    db_pool: &sqlx::PgPool,
+    principal: Principal,
+) -> Result<proto::LoginResponse, tonic::Status> {
+    let (user_id, admin_id) = owner(principal);
+    let refresh_token = token::generate();
+
+    let session_id = query_scalar!(
+        "INSERT INTO sessions (user_id, admin_id, refresh_token_hash, expires_at) VALUES ($1, $2, $3, now() + make_interval(secs => $4)) RETURNING session_id;",
+        user_id,
+        admin_id,
+        token::digest(&refresh_token),
+        REFRESH_TOKEN_TTL_SECS
+    )
+    .fetch_one(db_pool)
+    .await
+    .map_err(DbError::from)?;
+
+    login_response(db_pool, principal, session_id, refresh_token).await
+}
+
+/// Exchanges a refresh token for a new access token and a rotated refresh
+/// token. Presenting an already rotated token revokes the whole session.
+pub(crate) async fn refresh(
+    db_pool: &sqlx::PgPool,
+    refresh_token: &str,
+) -> Result<proto::LoginResponse, tonic::Status> {
+    let presented_hash = token::digest(refresh_token);
+    let rotated_token = token::generate();
+
+    let res = query!(
+        "UPDATE sessions SET refresh_token_hash = $1, previous_refresh_token_hash = refresh_token_hash, last_used_at = now(), expires_at = now() + make_interval(secs => $2) WHERE refresh_token_hash = $3 AND revoked_at IS NULL AND expires_at > now() RETURNING session_id, user_id, admin_id;",
+        token::digest(&rotated_token),
+        REFRESH_TOKEN_TTL_SECS,
+        presented_hash
+    )
+    .fetch_optional(db_pool)
+    .await
+    .map_err(DbError::from)?;
+
+    let Some(row) = res else {
+        // A rotated-out token being replayed means it leaked; kill the session.
+        query!(
+            "UPDATE sessions SET revoked_at = now() WHERE previous_refresh_token_hash = $1 AND revoked_at IS NULL;",
+            presented_hash
+        )
+        .execute(db_pool)
+        .await
+        .map_err(DbError::from)?;
+
+        return Err(tonic::Status::unauthenticated("Invalid refresh token"));
+    };
+
+    let principal = match (row.user_id, row.admin_id) {
+        (Some(user_id), _) => Principal::User(user_id),
+        (_, Some(admin_id)) => Principal::Admin(admin_id),
+        (None, None) => return Err(tonic::Status::unauthenticated("Invalid refresh token")),
+    };
+
+    login_response(db_pool, principal, row.session_id, rotated_token).await
+}
+
+/// Whether `session_id` still belongs to `principal` and has been neither
+/// revoked nor deleted along with its account.
+pub(crate) async fn is_active(
+    db_pool: &sqlx::PgPool,
+    principal: Principal,
+    session_id: i32,
+) -> Result<bool, tonic::Status> {
+    let (user_id, admin_id) = owner(principal);
+
+    query_scalar!(
+        r#"SELECT EXISTS (
+            SELECT 1 FROM sessions
+            WHERE session_id = $1
+                AND user_id IS NOT DISTINCT FROM $2
+                AND admin_id IS NOT DISTINCT FROM $3
+                AND revoked_at IS NULL
+                AND expires_at > now()
+        ) AS "active!";"#,
+        session_id,
+        user_id,
+        admin_id
+    )
+    .fetch_one(db_pool)
+    .await
+    .map_err(|e| DbError::from(e).into())
+}
+
+/// Revokes the sessions of `principal` other than `except_session_id`, so
+/// whoever knew its old password does not stay signed in.
+pub(crate) async fn revoke_others<'e, E>(
+    executor: E,
+    principal: Principal,
+    except_session_id: Option<i32>,
+) -> Result<(), tonic::Status>
+where
+    E: sqlx::PgExecutor<'e>,
+{
+    let (user_id, admin_id) = owner(principal);
+
+    query!(
+        r#"UPDATE sessions SET revoked_at = now()
+            WHERE user_id IS NOT DISTINCT FROM $1
+                AND admin_id IS NOT DISTINCT FROM $2
+                AND session_id IS DISTINCT FROM $3
+                AND revoked_at IS NULL;"#,
+        user_id,
+        admin_id,
+        except_session_id
+    )
+    .execute(executor)
+    .await
+    .map_err(DbError::from)?;
+
+    Ok(())
+}
+
+pub(crate) async fn list(
+    db_pool: &sqlx::PgPool,
+    principal: Principal,
+    current_session_id: i32,
+) -> Result<Vec<proto::Session>, tonic::Status> {
+    let (user_id, admin_id) = owner(principal);
+
+    let rows = query_as!(
+        SessionRow,
+        r#"SELECT session_id, created_at, last_used_at, expires_at, session_id = $3 AS "current!"
+            FROM sessions
+            WHERE user_id IS NOT DISTINCT FROM $1
+                AND admin_id IS NOT DISTINCT FROM $2
+                AND revoked_at IS NULL
+                AND expires_at > now()
+            ORDER BY last_used_at DESC;"#,
+        user_id,
+        admin_id,
+        current_session_id
+    )
+    .fetch_all(db_pool)
+    .await
+    .map_err(DbError::from)?;
+
+    Ok(rows.into_iter().map(Into::into).collect())
+}
+
+pub(crate) async fn revoke(
+    db_pool: &sqlx::PgPool,
+    principal: Principal,
+    session_id: i32,
+    current_session_id: i32,
+) -> Result<proto::Session, tonic::Status> {
+    let (user_id, admin_id) = owner(principal);
+
+    query_as!(
+        SessionRow,
+        r#"UPDATE sessions SET revoked_at = COALESCE(revoked_at, now())
+            WHERE session_id = $1
+                AND user_id IS NOT DISTINCT FROM $2
+                AND admin_id IS NOT DISTINCT FROM $3
+            RETURNING session_id, created_at, last_used_at, expires_at, session_id = $4 AS "current!";"#,
+        session_id,
+        user_id,
+        admin_id,
+        current_session_id
+    )
+    .fetch_optional(db_pool)
+    .await
+    .map_err(DbError::from)?
+    .map(Into::into)
+    .ok_or_else(|| tonic::Status::not_found("Session not found"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures;
+    use tonic::Code;
+
+    #[sqlx::test]
+    async fn refresh_rotates_the_token(db_pool: sqlx::PgPool) {
+        let principal = Principal::User(fixtures::user(&db_pool, "user").await);
+        let login = start(&db_pool, principal).await.unwrap();
+
+        let refreshed = refresh(&db_pool, &login.refresh_token).await.unwrap();
+        assert_eq!(refreshed.session_id, login.session_id);
+        assert_ne!(refreshed.refresh_token, login.refresh_token);
+        assert!(is_active(&db_pool, principal, login.session_id)
+            .await
+            .unwrap());
+
+        let again = refresh(&db_pool, &refreshed.refresh_token).await.unwrap();
+        assert_eq!(again.session_id, login.session_id);
+    }
+
+    #[sqlx::test]
+    async fn reusing_a_rotated_token_revokes_the_session(db_pool: sqlx::PgPool) {
+        let principal = Principal::User(fixtures::user(&db_pool, "user").await);
+        let login = start(&db_pool, principal).await.unwrap();
+        let refreshed = refresh(&db_pool, &login.refresh_token).await.unwrap();
+
+        let status = refresh(&db_pool, &login.refresh_token).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+        assert!(!is_active(&db_pool, principal, login.session_id)
+            .await
+            .unwrap());
+
+        // Whoever held the current token is signed out too.
+        let status = refresh(&db_pool, &refreshed.refresh_token)
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+    }
+
+    #[sqlx::test]
+    async fn revoking_others_keeps_the_current_session(db_pool: sqlx::PgPool) {
+        let principal = Principal::User(fixtures::user(&db_pool, "user").await);
+        let current = start(&db_pool, principal).await.unwrap();
+        let other = start(&db_pool, principal).await.unwrap();
+
+        revoke_others(&db_pool, principal, Some(current.session_id))
+            .await
+            .unwrap();
+
+        assert!(is_active(&db_pool, principal, current.session_id)
+            .await
+            .unwrap());
+        assert!(!is_active(&db_pool, principal, other.session_id)
+            .await
+            .unwrap());
+        let status = refresh(&db_pool, &other.refresh_token).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+    }
+}
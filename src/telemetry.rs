@@ -0,0 +1,142 @@
+use std::{
+    error::Error,
+    task::{Context, Poll},
+    time::Instant,
+};
+use tonic::{
+    codegen::{http, BoxFuture, Service},
+    transport::{server::TcpConnectInfo, Body},
+};
+use tower::Layer;
+use tracing::{field, Instrument};
+use tracing_subscriber::EnvFilter;
+
+pub(crate) const REQUEST_ID_HEADER: &str = "x-request-id";
+
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Installs the global subscriber. `LOG_FORMAT` is `pretty` (default) or
+/// `json`; levels come from `RUST_LOG` (e.g. `info,server::session=debug`)
+/// and default to `info`.
+pub(crate) fn init() -> Result<(), Box<dyn Error>> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+    let result = match std::env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => builder
+            .json()
+            .flatten_event(true)
+            .with_span_list(false)
+            .try_init(),
+        Ok("pretty") | Err(_) => builder.pretty().try_init(),
+        Ok(format) => return Err(format!("Unknown log format {}", format).into()),
+    };
+
+    result.map_err(|e| e as Box<dyn Error>)
+}
+
+fn is_valid_request_id(request_id: &str) -> bool {
+    !request_id.is_empty()
+        && request_id.len() <= MAX_REQUEST_ID_LEN
+        && request_id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// Runs every RPC inside an `rpc` span carrying its method, peer, request ID
+/// and (once [`crate::auth::AuthService`] has run) principal. The request ID is
+/// taken from `x-request-id` when the client sends a usable one, generated
+/// otherwise, and echoed back in the response headers.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct TraceLayer;
+
+impl<S> Layer<S> for TraceLayer {
+    type Service = TraceService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TraceService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct TraceService<S> {
+    inner: S,
+}
+
+impl<S, B> Service<http::Request<Body>> for TraceService<S>
+where
+    S: Service<http::Request<Body>, Response = http::Response<B>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: std::fmt::Debug,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: http::Request<Body>) -> Self::Future {
+        let request_id = request
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|request_id| is_valid_request_id(request_id))
+            .map(str::to_owned)
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        let header =
+            http::HeaderValue::from_str(&request_id).expect("request IDs are visible ASCII");
+        request
+            .headers_mut()
+            .insert(REQUEST_ID_HEADER, header.clone());
+
+        let span = tracing::info_span!(
+            "rpc",
+            method = request.uri().path(),
+            peer = field::Empty,
+            request_id = request_id.as_str(),
+            principal = field::Empty,
+        );
+
+        if let Some(peer) = request
+            .extensions()
+            .get::<TcpConnectInfo>()
+            .and_then(TcpConnectInfo::remote_addr)
+        {
+            span.record("peer", field::display(peer));
+        }
+
+        // Hand the future the service that was polled ready and keep the clone.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(
+            async move {
+                let started = Instant::now();
+                let result = inner.call(request).await;
+                let elapsed_ms = started.elapsed().as_millis() as u64;
+
+                match result {
+                    Ok(mut response) => {
+                        response.headers_mut().insert(REQUEST_ID_HEADER, header);
+
+                        // Unary errors come back trailers-only, with the status
+                        // in the headers; successful calls send it in trailers.
+                        let grpc_status = response
+                            .headers()
+                            .get("grpc-status")
+                            .and_then(|value| value.to_str().ok())
+                            .unwrap_or("0");
+                        tracing::info!(grpc_status, elapsed_ms, "RPC finished");
+
+                        Ok(response)
+                    }
+                    Err(e) => {
+                        tracing::error!(error = ?e, elapsed_ms, "RPC failed");
+                        Err(e)
+                    }
+                }
+            }
+            .instrument(span),
+        )
+    }
+}
@@ -0,0 +1,20 @@
+use sqlx::types::time::OffsetDateTime;
+
+pub(crate) fn to_proto(at: OffsetDateTime) -> prost_types::Timestamp {
+    prost_types::Timestamp {
+        seconds: at.unix_timestamp(),
+        nanos: at.nanosecond() as i32,
+    }
+}
+
+/// `None` if `timestamp` is out of range or not normalized.
+pub(crate) fn from_proto(timestamp: &prost_types::Timestamp) -> Option<OffsetDateTime> {
+    let nanos = u32::try_from(timestamp.nanos)
+        .ok()
+        .filter(|nanos| *nanos < 1_000_000_000)?;
+
+    OffsetDateTime::from_unix_timestamp(timestamp.seconds)
+        .ok()?
+        .replace_nanosecond(nanos)
+        .ok()
+}
@@ -0,0 +1,366 @@
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng, RngCore};
+use sha1::Sha1;
+use sqlx::{query, query_scalar};
+use std::time;
+use tonic::Code;
+
+use crate::error::{self, DbError};
+use crate::{timestamp, token};
+
+const ISSUER: &str = "rust_ecom";
+const SECRET_LEN: usize = 20;
+const STEP_SECS: u64 = 30;
+const DIGITS: u32 = 6;
+// Accept the previous and next step to tolerate clock drift.
+const SKEW_STEPS: i64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LEN: usize = 10;
+const MAX_FAILED_ATTEMPTS: i32 = 5;
+const LOCKOUT_SECS: f64 = 15.0 * 60.0;
+
+fn now() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// RFC 4226 HOTP value for `counter`.
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let code = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+
+    code % 10u32.pow(DIGITS)
+}
+
+/// Returns the RFC 6238 time step `code` is valid for, if any.
+fn matching_step(secret: &[u8], code: &str, now: u64) -> Option<i64> {
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let code: u32 = code.parse().ok()?;
+    let current = (now / STEP_SECS) as i64;
+
+    (current - SKEW_STEPS..=current + SKEW_STEPS)
+        .filter(|step| *step >= 0)
+        .find(|step| hotp(secret, *step as u64) == code)
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+pub(crate) fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0u8; SECRET_LEN];
+    rand::rngs::OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+pub(crate) fn encode_secret(secret: &[u8]) -> String {
+    BASE32_NOPAD.encode(secret)
+}
+
+pub(crate) fn otpauth_uri(account: &str, secret: &[u8]) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={DIGITS}&period={STEP_SECS}",
+        issuer = ISSUER,
+        account = account.replace(|c: char| !c.is_ascii_alphanumeric() && c != '@' && c != '.', "_"),
+        secret = encode_secret(secret),
+    )
+}
+
+/// Plaintext recovery codes, formatted as `xxxxx-xxxxx`, and their digests.
+pub(crate) fn generate_recovery_codes() -> Vec<(String, String)> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let raw: String = rand::rngs::OsRng
+                .sample_iter(&Alphanumeric)
+                .take(RECOVERY_CODE_LEN)
+                .map(|b| (b as char).to_ascii_lowercase())
+                .collect();
+            let (head, tail) = raw.split_at(RECOVERY_CODE_LEN / 2);
+            (format!("{}-{}", head, tail), token::digest(&raw))
+        })
+        .collect()
+}
+
+/// Whether any of the admin's roles demands TOTP while none is enabled yet.
+pub(crate) async fn enrollment_required(
+    db_pool: &sqlx::PgPool,
+    admin_id: i32,
+) -> Result<bool, tonic::Status> {
+    query_scalar!(
+        r#"SELECT EXISTS (
+            SELECT 1 FROM admin_roles
+            JOIN roles ON roles.role = admin_roles.role
+            WHERE admin_roles.admin_id = $1 AND roles.requires_totp
+        ) AND NOT EXISTS (
+            SELECT 1 FROM admin_totp WHERE admin_id = $1 AND enabled
+        ) AS "required!";"#,
+        admin_id
+    )
+    .fetch_one(db_pool)
+    .await
+    .map_err(|e| DbError::from(e).into())
+}
+
+pub(crate) async fn is_required(
+    db_pool: &sqlx::PgPool,
+    admin_id: i32,
+) -> Result<bool, tonic::Status> {
+    query_scalar!(
+        r#"SELECT EXISTS (
+            SELECT 1 FROM admin_roles
+            JOIN roles ON roles.role = admin_roles.role
+            WHERE admin_roles.admin_id = $1 AND roles.requires_totp
+        ) AS "required!";"#,
+        admin_id
+    )
+    .fetch_one(db_pool)
+    .await
+    .map_err(|e| DbError::from(e).into())
+}
+
+pub(crate) async fn is_enabled(
+    db_pool: &sqlx::PgPool,
+    admin_id: i32,
+) -> Result<bool, tonic::Status> {
+    query_scalar!(
+        r#"SELECT EXISTS (
+            SELECT 1 FROM admin_totp WHERE admin_id = $1 AND enabled
+        ) AS "enabled!";"#,
+        admin_id
+    )
+    .fetch_one(db_pool)
+    .await
+    .map_err(|e| DbError::from(e).into())
+}
+
+/// Counts an attempt as failed until [`reset_attempts`] says otherwise, and
+/// refuses it while the admin is locked out. Reaching the limit starts a
+/// lockout; the attempt that reaches it is still checked.
+async fn start_attempt(db_pool: &sqlx::PgPool, admin_id: i32) -> Result<(), tonic::Status> {
+    let row = query!(
+        r#"INSERT INTO admin_totp_attempts AS attempts (admin_id, failed_attempts) VALUES ($1, 1)
+            ON CONFLICT (admin_id) DO UPDATE SET
+                failed_attempts = CASE
+                    WHEN attempts.locked_until <= now() THEN 1
+                    ELSE attempts.failed_attempts + 1
+                END,
+                locked_until = CASE
+                    WHEN attempts.locked_until <= now() THEN NULL
+                    WHEN attempts.failed_attempts + 1 = $2 THEN now() + make_interval(secs => $3)
+                    ELSE attempts.locked_until
+                END
+            RETURNING failed_attempts, locked_until;"#,
+        admin_id,
+        MAX_FAILED_ATTEMPTS,
+        LOCKOUT_SECS
+    )
+    .fetch_one(db_pool)
+    .await
+    .map_err(DbError::from)?;
+
+    match (row.failed_attempts > MAX_FAILED_ATTEMPTS, row.locked_until) {
+        (true, Some(locked_until)) => Err(error::with_details(
+            Code::ResourceExhausted,
+            "Too many failed TOTP attempts, try again later",
+            vec![error::error_info(
+                "TOTP_LOCKED",
+                &[(
+                    "locked_until",
+                    &timestamp::to_proto(locked_until).to_string(),
+                )],
+            )],
+        )),
+        _ => Ok(()),
+    }
+}
+
+async fn reset_attempts(db_pool: &sqlx::PgPool, admin_id: i32) -> Result<(), tonic::Status> {
+    query!(
+        "DELETE FROM admin_totp_attempts WHERE admin_id = $1;",
+        admin_id
+    )
+    .execute(db_pool)
+    .await
+    .map_err(DbError::from)?;
+
+    Ok(())
+}
+
+async fn matches_totp(
+    db_pool: &sqlx::PgPool,
+    admin_id: i32,
+    code: &str,
+    pending: bool,
+) -> Result<bool, tonic::Status> {
+    let secret = query_scalar!(
+        "SELECT secret FROM admin_totp WHERE admin_id = $1 AND enabled = $2;",
+        admin_id,
+        !pending
+    )
+    .fetch_optional(db_pool)
+    .await
+    .map_err(DbError::from)?;
+
+    let Some(step) = secret.and_then(|secret| matching_step(&secret, code, now())) else {
+        return Ok(false);
+    };
+
+    let res = query!(
+        "UPDATE admin_totp SET last_used_step = $1 WHERE admin_id = $2 AND (last_used_step IS NULL OR last_used_step < $1);",
+        step,
+        admin_id
+    )
+    .execute(db_pool)
+    .await
+    .map_err(DbError::from)?;
+
+    Ok(res.rows_affected() == 1)
+}
+
+async fn matches_recovery_code(
+    db_pool: &sqlx::PgPool,
+    admin_id: i32,
+    code: &str,
+) -> Result<bool, tonic::Status> {
+    let res = query!(
+        "UPDATE admin_recovery_codes SET used_at = now() WHERE admin_id = $1 AND code_hash = $2 AND used_at IS NULL AND EXISTS (SELECT 1 FROM admin_totp WHERE admin_id = $1 AND enabled);",
+        admin_id,
+        token::digest(&normalize_recovery_code(code))
+    )
+    .execute(db_pool)
+    .await
+    .map_err(DbError::from)?;
+
+    Ok(res.rows_affected() == 1)
+}
+
+/// Checks a TOTP code against the admin's enabled (or, with `pending`, not yet
+/// confirmed) secret. Each time step can only be used once, and too many
+/// failures in a row lock the admin out for a while.
+pub(crate) async fn check_totp(
+    db_pool: &sqlx::PgPool,
+    admin_id: i32,
+    code: &str,
+    pending: bool,
+) -> Result<bool, tonic::Status> {
+    start_attempt(db_pool, admin_id).await?;
+
+    let valid = matches_totp(db_pool, admin_id, code, pending).await?;
+
+    if valid {
+        reset_attempts(db_pool, admin_id).await?;
+    }
+
+    Ok(valid)
+}
+
+/// Like [`check_totp`], also accepting and consuming a matching recovery code.
+pub(crate) async fn check(
+    db_pool: &sqlx::PgPool,
+    admin_id: i32,
+    code: &str,
+) -> Result<bool, tonic::Status> {
+    start_attempt(db_pool, admin_id).await?;
+
+    let valid = matches_totp(db_pool, admin_id, code, false).await?
+        || matches_recovery_code(db_pool, admin_id, code).await?;
+
+    if valid {
+        reset_attempts(db_pool, admin_id).await?;
+    }
+
+    Ok(valid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 4226 appendix D and RFC 6238 appendix B (SHA-1) shared secret.
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn hotp_matches_rfc_4226_vectors() {
+        let expected = [
+            755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+        ];
+
+        for (counter, code) in expected.into_iter().enumerate() {
+            assert_eq!(hotp(SECRET, counter as u64), code, "counter {}", counter);
+        }
+    }
+
+    #[test]
+    fn totp_matches_rfc_6238_vectors() {
+        // The RFC lists 8-digit codes; 6-digit ones are their last six digits.
+        let expected = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ];
+
+        for (time, code) in expected {
+            assert_eq!(
+                matching_step(SECRET, code, time),
+                Some((time / STEP_SECS) as i64),
+                "time {}",
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn totp_accepts_one_step_of_skew() {
+        let code = format!("{:06}", hotp(SECRET, 100));
+
+        assert_eq!(matching_step(SECRET, &code, 99 * STEP_SECS), Some(100));
+        assert_eq!(matching_step(SECRET, &code, 101 * STEP_SECS), Some(100));
+        assert_eq!(matching_step(SECRET, &code, 98 * STEP_SECS), None);
+        assert_eq!(matching_step(SECRET, &code, 102 * STEP_SECS), None);
+    }
+
+    #[test]
+    fn totp_rejects_malformed_codes() {
+        assert_eq!(matching_step(SECRET, "", 59), None);
+        assert_eq!(matching_step(SECRET, "28708", 59), None);
+        assert_eq!(matching_step(SECRET, "2870820", 59), None);
+        assert_eq!(matching_step(SECRET, "+87082", 59), None);
+        assert_eq!(matching_step(SECRET, "28708a", 59), None);
+    }
+
+    #[test]
+    fn recovery_codes_normalize_to_their_digest_input() {
+        let codes = generate_recovery_codes();
+
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        for (code, digest) in codes {
+            assert_eq!(code.len(), RECOVERY_CODE_LEN + 1);
+            assert_eq!(token::digest(&normalize_recovery_code(&code)), digest);
+            assert_eq!(
+                token::digest(&normalize_recovery_code(&code.to_uppercase())),
+                digest
+            );
+        }
+    }
+}
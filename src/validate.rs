@@ -0,0 +1,710 @@
+use std::marker::PhantomData;
+use tonic::{
+    codec::{Codec, DecodeBuf, Decoder, ProstCodec},
+    Code,
+};
+
+use crate::mask::Update;
+use crate::{error, proto};
+
+const NAME_MAX_LEN: usize = 200;
+const DESCRIPTION_MAX_LEN: usize = 5000;
+const NOTE_MAX_LEN: usize = 1000;
+const ROLE_MAX_LEN: usize = 64;
+const CODE_MAX_LEN: usize = 32;
+const TOKEN_MAX_LEN: usize = 512;
+const PASSWORD_MAX_LEN: usize = 128;
+const QUANTITY_MAX: i32 = 1000;
+const STOCK_DELTA_MAX: i32 = 1_000_000;
+const LOOKUP_MAX_ITEMS: usize = 1000;
+const FILTER_MAX_LEN: usize = 1000;
+const ORDER_BY_MAX_LEN: usize = 100;
+
+/// Field rules for a request message, checked as it is decoded.
+pub(crate) trait Validate {
+    /// `(field, description)` for every rule the message breaks.
+    fn violations(&self) -> Vec<(&'static str, String)>;
+}
+
+mod rule {
+    use crate::{money, proto, timestamp};
+
+    pub(super) type Result = std::result::Result<(), String>;
+
+    pub(super) fn required(value: &str) -> Result {
+        match value.trim().is_empty() {
+            true => Err("Must not be empty".to_owned()),
+            false => Ok(()),
+        }
+    }
+
+    pub(super) fn max_len(value: &str, max: usize) -> Result {
+        match value.chars().count() > max {
+            true => Err(format!("Must be at most {} characters", max)),
+            false => Ok(()),
+        }
+    }
+
+    pub(super) fn username(value: &str) -> Result {
+        let len = value.chars().count();
+        let valid_chars = value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+
+        match (3..=32).contains(&len) && valid_chars {
+            true => Ok(()),
+            false => {
+                Err("Must be 3 to 32 letters, digits, underscores, hyphens or dots".to_owned())
+            }
+        }
+    }
+
+    pub(super) fn password(value: &str) -> Result {
+        match (8..=super::PASSWORD_MAX_LEN).contains(&value.chars().count()) {
+            true => Ok(()),
+            false => Err(format!(
+                "Must be 8 to {} characters",
+                super::PASSWORD_MAX_LEN
+            )),
+        }
+    }
+
+    pub(super) fn email(value: &str) -> Result {
+        let valid = value.len() <= 254
+            && !value.chars().any(char::is_whitespace)
+            && match value.split_once('@') {
+                Some((local, domain)) => {
+                    !local.is_empty()
+                        && !domain.contains('@')
+                        && domain.split('.').count() >= 2
+                        && domain.split('.').all(|label| !label.is_empty())
+                }
+                None => false,
+            };
+
+        match valid {
+            true => Ok(()),
+            false => Err("Must be a valid email address".to_owned()),
+        }
+    }
+
+    pub(super) fn id(value: &i32) -> Result {
+        match *value > 0 {
+            true => Ok(()),
+            false => Err("Must be a positive id".to_owned()),
+        }
+    }
+
+    /// Ids where zero (unset) stands for the caller's own account.
+    pub(super) fn id_or_self(value: &i32) -> Result {
+        match *value >= 0 {
+            true => Ok(()),
+            false => Err("Must be a positive id, or 0 for your own account".to_owned()),
+        }
+    }
+
+    /// Ids where zero (unset) stands for every record.
+    pub(super) fn id_or_all(value: &i32) -> Result {
+        match *value >= 0 {
+            true => Ok(()),
+            false => Err("Must be a positive id, or 0 for all".to_owned()),
+        }
+    }
+
+    pub(super) fn ids(values: &[i32]) -> Result {
+        match values.iter().all(|value| *value > 0) {
+            true => Ok(()),
+            false => Err("Must only contain positive ids".to_owned()),
+        }
+    }
+
+    pub(super) fn max_items<T>(values: &[T], max: usize) -> Result {
+        match values.len() > max {
+            true => Err(format!("Must have at most {} items", max)),
+            false => Ok(()),
+        }
+    }
+
+    /// Zero for the default; sizes over the maximum are capped rather than refused.
+    pub(super) fn page_size(value: &i32) -> Result {
+        match *value >= 0 {
+            true => Ok(()),
+            false => Err("Must not be negative".to_owned()),
+        }
+    }
+
+    /// Zero for writes that don't check the version.
+    pub(super) fn version(value: &i32) -> Result {
+        match *value >= 0 {
+            true => Ok(()),
+            false => Err("Must not be negative".to_owned()),
+        }
+    }
+
+    pub(super) fn stock_delta(value: &i32) -> Result {
+        match *value != 0 && value.abs() <= super::STOCK_DELTA_MAX {
+            true => Ok(()),
+            false => Err(format!(
+                "Must be non-zero and at most {} either way",
+                super::STOCK_DELTA_MAX
+            )),
+        }
+    }
+
+    pub(super) fn order_status(value: &i32) -> Result {
+        match proto::OrderStatus::try_from(*value) {
+            Ok(status) if status != proto::OrderStatus::Unspecified => Ok(()),
+            _ => Err("Must be a known order status".to_owned()),
+        }
+    }
+
+    pub(super) fn movement_kind(value: &i32) -> Result {
+        match proto::MovementKind::try_from(*value) {
+            Ok(kind) if kind != proto::MovementKind::Unspecified => Ok(()),
+            _ => Err("Must be a known movement kind".to_owned()),
+        }
+    }
+
+    /// Line quantities, where zero means the RPC's default.
+    pub(super) fn quantity(value: &i32) -> Result {
+        match (0..=super::QUANTITY_MAX).contains(value) {
+            true => Ok(()),
+            false => Err(format!("Must be 0 to {}", super::QUANTITY_MAX)),
+        }
+    }
+
+    pub(super) fn positive_money(value: &Option<proto::Money>) -> Result {
+        let money = value.as_ref().ok_or("Required")?;
+        money::check(money)?;
+        match money.units > 0 || money.nanos > 0 {
+            true => Ok(()),
+            false => Err("Must be a positive amount".to_owned()),
+        }
+    }
+
+    /// Product prices, small enough that a line of [`super::QUANTITY_MAX`]
+    /// still fits the stored amounts.
+    pub(super) fn unit_price(value: &Option<proto::Money>) -> Result {
+        positive_money(value)?;
+        let max_units = money::MAX_UNITS / i64::from(super::QUANTITY_MAX);
+        match value.as_ref().is_some_and(|money| money.units <= max_units) {
+            true => Ok(()),
+            false => Err(format!("Must be at most {} units", max_units)),
+        }
+    }
+
+    pub(super) fn non_negative_money(value: &Option<proto::Money>) -> Result {
+        let money = value.as_ref().ok_or("Required")?;
+        money::check(money)?;
+        match money.units >= 0 && money.nanos >= 0 {
+            true => Ok(()),
+            false => Err("Must be zero or a positive amount".to_owned()),
+        }
+    }
+
+    pub(super) fn field_mask(value: &Option<prost_types::FieldMask>, fields: &[&str]) -> Result {
+        let mut paths = value.iter().flat_map(|mask| &mask.paths);
+
+        match paths.find(|path| !fields.contains(&path.as_str())) {
+            Some(path) => Err(format!(
+                "Unknown field `{}`, must be one of {}",
+                path,
+                fields.join(", ")
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Optional timestamps, which must be normalized and in range if set.
+    pub(super) fn timestamp(value: &Option<prost_types::Timestamp>) -> Result {
+        match value.as_ref().map(timestamp::from_proto) {
+            Some(None) => Err("Must be a valid timestamp".to_owned()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Implements [`Validate`] from a list of `field: [rule, rule(arg), ...]`,
+/// where each rule is a function in [`rule`] taking the field (and args).
+/// Fields written as `field if writes: [...]` are only checked when the
+/// request's update mask writes them.
+macro_rules! rules {
+    ($($message:ident { $($field:ident $(if $guard:ident)?: [$($rule:ident $(($($arg:expr),*))?),*]),* $(,)? })*) => {$(
+        impl Validate for proto::$message {
+            fn violations(&self) -> Vec<(&'static str, String)> {
+                #[allow(unused_mut)]
+                let mut violations = Vec::new();
+
+                $(
+                    if true $(&& self.$guard(stringify!($field)))? {
+                        $(
+                            if let Err(description) = rule::$rule(&self.$field $($(, $arg)*)?) {
+                                violations.push((stringify!($field), description));
+                            }
+                        )*
+                    }
+                )*
+
+                violations
+            }
+        }
+    )*};
+}
+
+rules! {
+    Empty {}
+
+    // Products
+    GetProductsRequest {
+        page_size: [page_size],
+        page_token: [max_len(TOKEN_MAX_LEN)],
+        filter: [max_len(FILTER_MAX_LEN)],
+        order_by: [max_len(ORDER_BY_MAX_LEN)],
+    }
+    GetUserProductsRequest {
+        user_id: [id_or_self],
+        page_size: [page_size],
+        page_token: [max_len(TOKEN_MAX_LEN)],
+        filter: [max_len(FILTER_MAX_LEN)],
+        order_by: [max_len(ORDER_BY_MAX_LEN)],
+    }
+    GetProductRequest { product_id: [id] }
+    CreateProductRequest {
+        name: [required, max_len(NAME_MAX_LEN)],
+        description: [max_len(DESCRIPTION_MAX_LEN)],
+        price: [unit_price],
+    }
+    UpdateProductRequest {
+        product_id: [id],
+        name if writes: [required, max_len(NAME_MAX_LEN)],
+        description if writes: [max_len(DESCRIPTION_MAX_LEN)],
+        price if writes: [unit_price],
+        update_mask: [field_mask(proto::UpdateProductRequest::FIELDS)],
+        expected_version: [version],
+    }
+    DeleteProductRequest {
+        product_id: [id],
+        expected_version: [version],
+    }
+
+    // Inventory
+    GetStockLevelsRequest { product_ids: [max_items(LOOKUP_MAX_ITEMS), ids] }
+    AdjustStockRequest {
+        product_id: [id],
+        delta: [stock_delta],
+        kind: [movement_kind],
+        note: [max_len(NOTE_MAX_LEN)],
+    }
+    ListInventoryMovementsRequest {
+        product_id: [id_or_all],
+        since: [timestamp],
+        until: [timestamp],
+        page_size: [page_size],
+        page_token: [max_len(TOKEN_MAX_LEN)],
+        filter: [max_len(FILTER_MAX_LEN)],
+        order_by: [max_len(ORDER_BY_MAX_LEN)],
+    }
+
+    // Orders
+    GetOrdersRequest {
+        page_size: [page_size],
+        page_token: [max_len(TOKEN_MAX_LEN)],
+        filter: [max_len(FILTER_MAX_LEN)],
+        order_by: [max_len(ORDER_BY_MAX_LEN)],
+    }
+    GetUserOrdersRequest {
+        user_id: [id_or_self],
+        page_size: [page_size],
+        page_token: [max_len(TOKEN_MAX_LEN)],
+        filter: [max_len(FILTER_MAX_LEN)],
+        order_by: [max_len(ORDER_BY_MAX_LEN)],
+    }
+    GetOrderRequest { order_id: [id] }
+    UpdateOrderRequest {
+        order_id: [id],
+        user_id if writes: [id],
+        total if writes: [non_negative_money],
+        update_mask: [field_mask(proto::UpdateOrderRequest::FIELDS)],
+        expected_version: [version],
+    }
+    TransitionOrderRequest {
+        order_id: [id],
+        status: [order_status],
+        note: [max_len(NOTE_MAX_LEN)],
+    }
+    DeleteOrderRequest {
+        order_id: [id],
+        expected_version: [version],
+    }
+
+    // Admin Accounts
+    GetAdminAccountsRequest {
+        page_size: [page_size],
+        page_token: [max_len(TOKEN_MAX_LEN)],
+        filter: [max_len(FILTER_MAX_LEN)],
+        order_by: [max_len(ORDER_BY_MAX_LEN)],
+    }
+    GetAdminAccountRequest { admin_id: [id] }
+    CreateAdminAccountRequest {
+        username: [username],
+        password: [password],
+        email: [email],
+    }
+    UpdateAdminAccountRequest {
+        username if writes: [username],
+        password if writes: [password],
+        email if writes: [email],
+        update_mask: [field_mask(proto::UpdateAdminAccountRequest::FIELDS)],
+        expected_version: [version],
+    }
+    DeleteAdminAccountRequest {
+        admin_id: [id],
+        expected_version: [version],
+    }
+    AssignAdminRoleRequest {
+        admin_id: [id],
+        role: [required, max_len(ROLE_MAX_LEN)],
+    }
+    RevokeAdminRoleRequest {
+        admin_id: [id],
+        role: [required, max_len(ROLE_MAX_LEN)],
+    }
+
+    // User Accounts
+    GetUserAccountsRequest {
+        page_size: [page_size],
+        page_token: [max_len(TOKEN_MAX_LEN)],
+        filter: [max_len(FILTER_MAX_LEN)],
+        order_by: [max_len(ORDER_BY_MAX_LEN)],
+    }
+    GetUserAccountRequest { user_id: [id_or_self] }
+    CreateUserAccountRequest {
+        username: [username],
+        password: [password],
+        email: [email],
+    }
+    UpdateUserAccountRequest {
+        user_id: [id_or_self],
+        username if writes: [username],
+        password if writes: [password],
+        email if writes: [email],
+        update_mask: [field_mask(proto::UpdateUserAccountRequest::FIELDS)],
+        expected_version: [version],
+    }
+    DeleteUserAccountRequest {
+        user_id: [id_or_self],
+        expected_version: [version],
+    }
+
+    // Cart
+    AddToCartRequest {
+        product_id: [id],
+        user_id: [id_or_self],
+        quantity: [quantity],
+    }
+    RemoveFromCartRequest {
+        product_id: [id],
+        user_id: [id_or_self],
+        quantity: [quantity],
+    }
+    CheckoutRequest {}
+
+    // Authentication
+    LoginRequest {
+        username: [required, max_len(NAME_MAX_LEN)],
+        password: [required, max_len(PASSWORD_MAX_LEN)],
+    }
+    VerifyAdminTotpRequest {
+        totp_challenge: [required, max_len(TOKEN_MAX_LEN)],
+        code: [required, max_len(CODE_MAX_LEN)],
+    }
+    RefreshTokenRequest { refresh_token: [required, max_len(TOKEN_MAX_LEN)] }
+
+    // Account Recovery
+    RequestPasswordResetRequest { email: [email] }
+    ConfirmPasswordResetRequest {
+        token: [required, max_len(TOKEN_MAX_LEN)],
+        new_password: [password],
+    }
+    VerifyEmailRequest { token: [required, max_len(TOKEN_MAX_LEN)] }
+
+    // Sessions
+    RevokeSessionRequest { session_id: [id] }
+
+    // Two-Factor Authentication
+    ConfirmTotpRequest { code: [required, max_len(CODE_MAX_LEN)] }
+    DisableTotpRequest { code: [required, max_len(CODE_MAX_LEN)] }
+}
+
+fn invalid_argument(violations: Vec<(&'static str, String)>) -> tonic::Status {
+    let message = violations
+        .iter()
+        .map(|(field, description)| format!("{}: {}", field, description))
+        .collect::<Vec<_>>()
+        .join("; ");
+    let violations: Vec<(&str, &str)> = violations
+        .iter()
+        .map(|(field, description)| (*field, description.as_str()))
+        .collect();
+
+    error::with_details(
+        Code::InvalidArgument,
+        message,
+        vec![error::bad_request(&violations)],
+    )
+}
+
+/// `ProstCodec` that rejects requests breaking their [`Validate`] rules with
+/// `INVALID_ARGUMENT` before the handler runs. build.rs points the generated
+/// servers at it.
+#[derive(Debug, Clone)]
+pub(crate) struct ValidatingCodec<T, U>(PhantomData<(T, U)>);
+
+impl<T, U> Default for ValidatingCodec<T, U> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<T, U> Codec for ValidatingCodec<T, U>
+where
+    T: prost::Message + Send + 'static,
+    U: prost::Message + Default + Validate + Send + 'static,
+{
+    type Encode = T;
+    type Decode = U;
+
+    type Encoder = <ProstCodec<T, U> as Codec>::Encoder;
+    type Decoder = ValidatingDecoder<<ProstCodec<T, U> as Codec>::Decoder>;
+
+    fn encoder(&mut self) -> Self::Encoder {
+        ProstCodec::<T, U>::default().encoder()
+    }
+
+    fn decoder(&mut self) -> Self::Decoder {
+        ValidatingDecoder(ProstCodec::<T, U>::default().decoder())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ValidatingDecoder<D>(D);
+
+impl<D> Decoder for ValidatingDecoder<D>
+where
+    D: Decoder<Error = tonic::Status>,
+    D::Item: Validate,
+{
+    type Item = D::Item;
+    type Error = tonic::Status;
+
+    fn decode(&mut self, buf: &mut DecodeBuf<'_>) -> Result<Option<Self::Item>, Self::Error> {
+        let Some(item) = self.0.decode(buf)? else {
+            return Ok(None);
+        };
+
+        match item.violations() {
+            violations if violations.is_empty() => Ok(Some(item)),
+            violations => Err(invalid_argument(violations)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usd(units: i64, nanos: i32) -> Option<proto::Money> {
+        Some(proto::Money {
+            currency_code: "USD".to_owned(),
+            units,
+            nanos,
+        })
+    }
+
+    #[test]
+    fn text_rules_count_characters() {
+        assert!(rule::required("x").is_ok());
+        assert_eq!(rule::required(" \t").unwrap_err(), "Must not be empty");
+
+        assert!(rule::max_len(&"é".repeat(5), 5).is_ok());
+        assert_eq!(
+            rule::max_len(&"é".repeat(6), 5).unwrap_err(),
+            "Must be at most 5 characters"
+        );
+    }
+
+    #[test]
+    fn usernames_are_3_to_32_safe_characters() {
+        for valid in ["abc", "a.b-c_d", &"a".repeat(32)] {
+            assert!(rule::username(valid).is_ok(), "{}", valid);
+        }
+        for invalid in ["ab", &"a".repeat(33), "a b c", "abc!", "ünï"] {
+            assert!(rule::username(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn passwords_are_8_to_128_characters() {
+        assert!(rule::password(&"x".repeat(8)).is_ok());
+        assert!(rule::password(&"x".repeat(PASSWORD_MAX_LEN)).is_ok());
+        assert_eq!(
+            rule::password(&"x".repeat(7)).unwrap_err(),
+            "Must be 8 to 128 characters"
+        );
+        assert!(rule::password(&"x".repeat(PASSWORD_MAX_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn emails_need_a_local_part_and_a_dotted_domain() {
+        for valid in ["a@b.co", "first.last+tag@mail.example.com"] {
+            assert!(rule::email(valid).is_ok(), "{}", valid);
+        }
+
+        let too_long = format!("{}@example.com", "a".repeat(243));
+        for invalid in [
+            "",
+            "a.example.com",
+            "@example.com",
+            "a@example",
+            "a@b@example.com",
+            "a@example..com",
+            "a@.example.com",
+            "a b@example.com",
+            &too_long,
+        ] {
+            assert!(rule::email(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn id_rules_treat_zero_by_meaning() {
+        assert!(rule::id(&1).is_ok());
+        assert_eq!(rule::id(&0).unwrap_err(), "Must be a positive id");
+        assert!(rule::id_or_self(&0).is_ok());
+        assert!(rule::id_or_self(&-1).is_err());
+        assert!(rule::id_or_all(&0).is_ok());
+        assert!(rule::id_or_all(&-1).is_err());
+        assert!(rule::ids(&[1, 2]).is_ok());
+        assert!(rule::ids(&[1, 0]).is_err());
+        assert!(rule::max_items(&[0; 3], 3).is_ok());
+        assert!(rule::max_items(&[0; 4], 3).is_err());
+    }
+
+    #[test]
+    fn counts_stay_within_their_limits() {
+        assert!(rule::page_size(&0).is_ok());
+        assert!(rule::page_size(&-1).is_err());
+        assert!(rule::version(&0).is_ok());
+        assert!(rule::version(&-1).is_err());
+
+        for quantity in [0, QUANTITY_MAX] {
+            assert!(rule::quantity(&quantity).is_ok(), "{}", quantity);
+        }
+        for quantity in [-1, QUANTITY_MAX + 1] {
+            assert_eq!(rule::quantity(&quantity).unwrap_err(), "Must be 0 to 1000");
+        }
+
+        for delta in [1, -1, STOCK_DELTA_MAX, -STOCK_DELTA_MAX] {
+            assert!(rule::stock_delta(&delta).is_ok(), "{}", delta);
+        }
+        for delta in [0, STOCK_DELTA_MAX + 1, -STOCK_DELTA_MAX - 1] {
+            assert!(rule::stock_delta(&delta).is_err(), "{}", delta);
+        }
+    }
+
+    #[test]
+    fn enums_must_be_known_and_specified() {
+        assert!(rule::order_status(&(proto::OrderStatus::Paid as i32)).is_ok());
+        assert!(rule::order_status(&0).is_err());
+        assert!(rule::order_status(&99).is_err());
+        assert!(rule::movement_kind(&(proto::MovementKind::Receipt as i32)).is_ok());
+        assert!(rule::movement_kind(&0).is_err());
+        assert!(rule::movement_kind(&99).is_err());
+    }
+
+    #[test]
+    fn unit_prices_are_positive_and_bounded() {
+        let max_units = crate::money::MAX_UNITS / i64::from(QUANTITY_MAX);
+
+        assert!(rule::unit_price(&usd(0, 100_000)).is_ok());
+        assert!(rule::unit_price(&usd(max_units, 999_900_000)).is_ok());
+        assert_eq!(
+            rule::unit_price(&usd(max_units + 1, 0)).unwrap_err(),
+            "Must be at most 999999999999 units"
+        );
+        assert_eq!(
+            rule::unit_price(&usd(0, 0)).unwrap_err(),
+            "Must be a positive amount"
+        );
+        assert_eq!(rule::unit_price(&None).unwrap_err(), "Required");
+
+        assert!(rule::non_negative_money(&usd(0, 0)).is_ok());
+        assert_eq!(
+            rule::non_negative_money(&usd(0, -100_000)).unwrap_err(),
+            "Must be zero or a positive amount"
+        );
+    }
+
+    #[test]
+    fn field_masks_and_timestamps_must_be_valid() {
+        let mask = |paths: &[&str]| {
+            Some(prost_types::FieldMask {
+                paths: paths.iter().map(|path| path.to_string()).collect(),
+            })
+        };
+        assert!(rule::field_mask(&None, &["name"]).is_ok());
+        assert!(rule::field_mask(&mask(&["name"]), &["name", "price"]).is_ok());
+        assert_eq!(
+            rule::field_mask(&mask(&["name", "sku"]), &["name", "price"]).unwrap_err(),
+            "Unknown field `sku`, must be one of name, price"
+        );
+
+        let at = |seconds, nanos| Some(prost_types::Timestamp { seconds, nanos });
+        assert!(rule::timestamp(&None).is_ok());
+        assert!(rule::timestamp(&at(0, 999_999_999)).is_ok());
+        assert!(rule::timestamp(&at(0, 1_000_000_000)).is_err());
+        assert!(rule::timestamp(&at(0, -1)).is_err());
+    }
+
+    #[test]
+    fn messages_report_every_broken_rule() {
+        let violations = proto::CreateUserAccountRequest::default().violations();
+        let fields: Vec<&str> = violations.iter().map(|(field, _)| *field).collect();
+        assert_eq!(fields, ["username", "password", "email"]);
+
+        let status = invalid_argument(
+            proto::AddToCartRequest {
+                product_id: 0,
+                user_id: -1,
+                quantity: 1,
+            }
+            .violations(),
+        );
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(
+            status.message(),
+            "product_id: Must be a positive id; user_id: Must be a positive id, or 0 for your own account"
+        );
+    }
+
+    #[test]
+    fn updates_only_check_the_fields_they_write() {
+        let request = proto::UpdateProductRequest {
+            product_id: 1,
+            description: "New".to_owned(),
+            ..Default::default()
+        };
+        assert!(request.violations().is_empty());
+
+        let request = proto::UpdateProductRequest {
+            update_mask: Some(prost_types::FieldMask {
+                paths: vec!["name".to_owned()],
+            }),
+            ..request
+        };
+        assert_eq!(
+            request.violations(),
+            [("name", "Must not be empty".to_owned())]
+        );
+    }
+}